@@ -1,10 +1,12 @@
 use std::{
+	collections::HashSet,
 	fs::{File, OpenOptions},
 	io::{Seek, SeekFrom, Write},
-	path::Path,
+	path::{Path, PathBuf},
 	sync::RwLock,
 };
 
+use crc32fast::Hasher;
 use hcomp::{encode::encode, Heightmap};
 use libwebp_sys::{
 	WebPEncode,
@@ -15,68 +17,262 @@ use libwebp_sys::{
 	WebPPictureInit,
 };
 
-use crate::{map_lat_lon_to_index, Dataset, TileMetadata, FORMAT_VERSION};
+use crate::{
+	journal::Journal,
+	map_lat_lon_to_index,
+	Dataset,
+	JournalRecovery,
+	LoadError,
+	TileEntry,
+	TileMetadata,
+	TileOrder,
+	FORMAT_VERSION,
+};
 
 struct Locked {
-	tile_map: Vec<u64>,
+	tile_map: Vec<TileEntry>,
 	file: File,
+	journal: Option<Journal>,
+	pending: Option<Pending>,
+}
+
+/// Encoded tiles waiting to be written in order.
+struct Pending {
+	order: TileOrder,
+	capacity: usize,
+	bytes: usize,
+	tiles: Vec<(usize, [Vec<u8>; 3], u32)>,
+	/// The indices of `tiles`, for [`DatasetBuilder::tile_exists`].
+	indices: HashSet<usize>,
+}
+
+impl Locked {
+	/// Append tiles to the end of the file, and record them in the journal.
+	fn append<'a>(
+		&mut self, tiles: impl IntoIterator<Item = (usize, [&'a [u8]; 3], u32)>,
+	) -> Result<(), std::io::Error> {
+		let mut offset = self.file.seek(SeekFrom::End(0))?;
+		let mut records = Vec::new();
+		for (index, layers, checksum) in tiles {
+			let entry = TileEntry {
+				offset,
+				layer_lengths: layers.map(|x| x.len() as u32),
+				checksum,
+			};
+			for layer in layers {
+				self.file.write_all(layer)?;
+			}
+			offset += entry.data_len();
+			records.push((index, entry));
+		}
+
+		if let Some(journal) = &mut self.journal {
+			// The data must be on disk before the journal refers to it.
+			self.file.sync_data()?;
+			journal.append(&records)?;
+		}
+		for (index, entry) in records {
+			self.tile_map[index] = entry;
+		}
+
+		Ok(())
+	}
+
+	fn write_pending(&mut self, metadata: &TileMetadata) -> Result<(), std::io::Error> {
+		let (order, mut tiles) = match &mut self.pending {
+			Some(pending) => {
+				pending.bytes = 0;
+				pending.indices.clear();
+				(pending.order, std::mem::take(&mut pending.tiles))
+			},
+			None => return Ok(()),
+		};
+
+		tiles.sort_by_cached_key(|&(index, ..)| order.key(metadata, index));
+		self.append(
+			tiles
+				.iter()
+				.map(|(index, layers, checksum)| (*index, [&layers[0][..], &layers[1][..], &layers[2][..]], *checksum)),
+		)
+	}
 }
 
 pub struct DatasetBuilder {
+	path: PathBuf,
 	metadata: TileMetadata,
 	locked: RwLock<Locked>,
 }
 
 impl DatasetBuilder {
-	pub fn from_dataset(path: &Path, dataset: Dataset) -> Result<Self, std::io::Error> {
-		let metadata = dataset.metadata;
-		let tile_map = dataset.tile_map;
+	/// Resume building the dataset at `path`, recording added tiles in a journal next to it.
+	///
+	/// The file is checked to have `metadata` before anything is written, and is left untouched if it doesn't, with
+	/// [`LoadError::MetadataMismatch`]. Then everything a previous run completed is recovered: an interrupted flush of
+	/// the offset table is repaired, tiles left in the journal are added back, and unreferenced bytes at the end of the
+	/// file are removed.
+	pub fn open(path: &Path, metadata: TileMetadata) -> Result<(Self, JournalRecovery), LoadError> {
+		// A flush that was interrupted while writing the table may have left a torn header, which the snapshot
+		// replaces.
+		let stored = match Self::read_snapshot(path)? {
+			Some(table) => Dataset::read_metadata(table[0..32].try_into().unwrap())?,
+			None => Dataset::load(path)?.metadata,
+		};
+		if stored.version != FORMAT_VERSION {
+			return Err(LoadError::UnsupportedFormatVersion);
+		}
+		if stored != metadata {
+			return Err(LoadError::MetadataMismatch);
+		}
+
+		let mut recovery = JournalRecovery {
+			table_restored: Self::restore_table(path)?,
+			..Default::default()
+		};
+
+		let dataset = Dataset::load(path)?;
+		let mut tile_map = dataset.tile_map;
 		drop(dataset.data);
 
-		Ok(Self {
+		let mut file = OpenOptions::new().write(true).read(true).open(path)?;
+		let mut journal = Journal::open(path)?;
+		let len = file.seek(SeekFrom::End(0))?;
+
+		// Records left over from an unrelated dataset point past the end of the file.
+		for (index, entry) in journal.replay()? {
+			if index >= tile_map.len() || entry.offset + entry.data_len() > len {
+				break;
+			}
+			tile_map[index] = entry;
+			recovery.tiles += 1;
+		}
+
+		let end = tile_map
+			.iter()
+			.filter(|entry| entry.exists())
+			.map(|entry| entry.offset + entry.data_len())
+			.max()
+			.unwrap_or(0)
+			.max(metadata.header_size() as u64);
+		if len > end {
+			file.set_len(end)?;
+			recovery.reclaimed_bytes = len - end;
+		}
+
+		let builder = Self {
+			path: path.to_owned(),
 			metadata,
 			locked: RwLock::new(Locked {
 				tile_map,
-				file: OpenOptions::new().write(true).read(true).open(path)?,
+				file,
+				journal: Some(journal),
+				pending: None,
 			}),
-		})
+		};
+
+		// Move the recovered tiles into the offset table.
+		builder.flush()?;
+
+		Ok((builder, recovery))
 	}
 
+	/// Create a new dataset at `path`, replacing any file that is already there.
 	pub fn new(path: &Path, metadata: TileMetadata) -> Result<Self, std::io::Error> {
 		assert_eq!(
 			metadata.version, FORMAT_VERSION,
 			"Can only build datasets with version {}",
 			FORMAT_VERSION
 		);
+		assert!(
+			metadata.tiles_per_degree > 0 && metadata.tiles_per_degree <= Dataset::MAX_TILES_PER_DEGREE,
+			"Tiles per degree must be in 1..={}",
+			Dataset::MAX_TILES_PER_DEGREE
+		);
+		assert!(
+			metadata.has_valid_width_bands(),
+			"Width bands must leave an even number of pixels in each tile"
+		);
 
-		let tile_map = vec![0; 360 * 180];
+		let tile_map = vec![TileEntry::default(); metadata.tile_count()];
 
 		let mut file = File::create(path)?;
-		Self::write_to_file(&mut file, metadata, &tile_map)?;
+		file.write_all(&Self::encode_table(metadata, &tile_map))?;
+
+		// A snapshot left by the replaced file must not be restored over this one.
+		for suffix in [".table", ".table.tmp"] {
+			match std::fs::remove_file(snapshot_path(path, suffix)) {
+				Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+				_ => {},
+			}
+		}
 
 		Ok(Self {
+			path: path.to_owned(),
 			metadata,
-			locked: RwLock::new(Locked { tile_map, file }),
+			locked: RwLock::new(Locked {
+				tile_map,
+				file,
+				journal: None,
+				pending: None,
+			}),
 		})
 	}
 
+	/// Record every added tile in a journal next to the file, so that no completed tiles are lost if the process exits
+	/// before the offset table is flushed. The tiles can be recovered with [`open`](Self::open).
+	///
+	/// Records left in the journal by a dataset that was previously at the same path are discarded.
+	pub fn with_journal(self) -> Result<Self, std::io::Error> {
+		let mut journal = Journal::open(&self.path)?;
+		journal.clear()?;
+		self.locked.write().unwrap().journal = Some(journal);
+
+		Ok(self)
+	}
+
+	/// Buffer up to `buffer_size` bytes of encoded tiles, and write them to the file sorted by `order`, so that tiles
+	/// added in roughly that order end up close to each other.
+	///
+	/// Buffered tiles are written when the buffer is full and by [`flush`](Self::flush). They are not in the journal
+	/// until then, so they are lost if the process exits before that.
+	pub fn with_order(self, order: TileOrder, buffer_size: usize) -> Self {
+		self.locked.write().unwrap().pending = Some(Pending {
+			order,
+			capacity: buffer_size,
+			bytes: 0,
+			tiles: Vec::new(),
+			indices: HashSet::new(),
+		});
+
+		self
+	}
+
+	pub fn metadata(&self) -> TileMetadata { self.metadata }
+
 	pub fn tile_exists(&self, lat: i16, lon: i16) -> bool {
-		let index = map_lat_lon_to_index(lat, lon);
-		self.locked.read().unwrap().tile_map[index] != 0
+		let index = map_lat_lon_to_index(lat, lon, self.metadata.tiles_per_degree);
+		let locked = self.locked.read().unwrap();
+		locked.tile_map[index].exists()
+			|| locked
+				.pending
+				.as_ref()
+				.is_some_and(|pending| pending.indices.contains(&index))
 	}
 
-	/// data: `height + 500`s in meters.
+	/// data: `height + 500`s in meters, with `metadata.tile_width(lat)` columns and `metadata.resolution` rows.
 	pub fn add_tile(
 		&self, lat: i16, lon: i16, data: Vec<u16>, water: Vec<u8>, hillshade: Vec<u8>,
 	) -> Result<(), std::io::Error> {
+		let width = self.metadata.tile_width(lat) as u32;
+		let height = self.metadata.resolution as u32;
+
 		let water = {
 			tracy::zone!("Compress water");
-			Self::compress_u8_webp(&water, self.metadata.resolution as _, self.metadata.resolution as _)?
+			Self::compress_u8_webp(&water, width, height)?
 		};
 
 		let hillshade = {
 			tracy::zone!("Compress hillshade");
-			Self::compress_u8_webp(&hillshade, self.metadata.resolution as _, self.metadata.resolution as _)?
+			Self::compress_u8_webp(&hillshade, width, height)?
 		};
 
 		let data: Vec<_> = {
@@ -96,8 +292,8 @@ impl DatasetBuilder {
 
 			encode(
 				Heightmap {
-					width: self.metadata.resolution as _,
-					height: self.metadata.resolution as _,
+					width: width as _,
+					height: height as _,
 					data: data.into(),
 				},
 				22,
@@ -107,14 +303,73 @@ impl DatasetBuilder {
 			out
 		};
 
+		self.write_tile(lat, lon, [&data, &water, &hillshade])
+	}
+
+	/// Add a tile that is already encoded, such as one read with [`Dataset::raw_tile`]. `layer_lengths` splits `data`
+	/// into the height, water, and hillshade layers.
+	pub fn add_raw_tile(&self, lat: i16, lon: i16, layer_lengths: [u32; 3], data: &[u8]) -> Result<(), std::io::Error> {
+		let [height_len, water_len, hillshade_len] = layer_lengths.map(|x| x as usize);
+		if height_len + water_len + hillshade_len != data.len() {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				"Layer lengths do not match the tile data",
+			));
+		}
+
+		let (height, rest) = data.split_at(height_len);
+		let (water, hillshade) = rest.split_at(water_len);
+		self.write_tile(lat, lon, [height, water, hillshade])
+	}
+
+	/// Add a tile whose layers are already encoded, such as ones read with [`Dataset::raw_layers`].
+	pub fn add_raw_layers(&self, lat: i16, lon: i16, layers: [&[u8]; 3]) -> Result<(), std::io::Error> {
+		self.write_tile(lat, lon, layers)
+	}
+
+	/// Add a tile whose height and water layers are already encoded, and encode `hillshade`, for sources that don't
+	/// store one.
+	pub fn add_raw_tile_with_hillshade(
+		&self, lat: i16, lon: i16, height: &[u8], water: &[u8], hillshade: &[u8],
+	) -> Result<(), std::io::Error> {
+		let hillshade = {
+			tracy::zone!("Compress hillshade");
+			Self::compress_u8_webp(
+				hillshade,
+				self.metadata.tile_width(lat) as u32,
+				self.metadata.resolution as u32,
+			)?
+		};
+
+		self.write_tile(lat, lon, [height, water, &hillshade])
+	}
+
+	fn write_tile(&self, lat: i16, lon: i16, layers: [&[u8]; 3]) -> Result<(), std::io::Error> {
+		let checksum = {
+			tracy::zone!("Checksum");
+			let mut hasher = Hasher::new();
+			for layer in layers {
+				hasher.update(layer);
+			}
+			hasher.finalize()
+		};
+
 		tracy::zone!("Write");
-		let index = map_lat_lon_to_index(lat, lon);
+		let index = map_lat_lon_to_index(lat, lon, self.metadata.tiles_per_degree);
 		let mut locked = self.locked.write().unwrap();
-		let offset = locked.file.seek(SeekFrom::End(0))?;
-		locked.tile_map[index] = offset;
-		locked.file.write_all(&data)?;
-		locked.file.write_all(&water)?;
-		locked.file.write_all(&hillshade)?;
+		let full = match &mut locked.pending {
+			Some(pending) => {
+				pending.bytes += layers.iter().map(|x| x.len()).sum::<usize>();
+				pending.tiles.push((index, layers.map(|x| x.to_vec()), checksum));
+				pending.indices.insert(index);
+				pending.bytes >= pending.capacity
+			},
+			None => return locked.append([(index, layers, checksum)]),
+		};
+
+		if full {
+			locked.write_pending(&self.metadata)?;
+		}
 
 		Ok(())
 	}
@@ -123,29 +378,109 @@ impl DatasetBuilder {
 		tracy::zone!("Flush");
 
 		let mut locked = self.locked.write().unwrap();
+		let locked = &mut *locked;
+		locked.write_pending(&self.metadata)?;
+
+		// The checksum covers the offset table, so the header is rewritten too.
+		let table = Self::encode_table(self.metadata, &locked.tile_map);
+
+		// Overwriting the table in place can be torn by a crash, so a complete copy is made durable first, and
+		// restored by `restore_table`.
+		let snapshot = snapshot_path(&self.path, ".table");
+		let temp = snapshot_path(&self.path, ".table.tmp");
+		{
+			let mut file = File::create(&temp)?;
+			file.write_all(&table)?;
+			file.sync_all()?;
+		}
+		std::fs::rename(&temp, &snapshot)?;
 
-		locked.file.seek(SeekFrom::Start(32))?;
-		let slice = unsafe { std::slice::from_raw_parts(locked.tile_map.as_ptr() as _, locked.tile_map.len() * 8) };
-		locked.file.write_all(slice)?;
+		locked.file.seek(SeekFrom::Start(0))?;
+		locked.file.write_all(&table)?;
+		locked.file.sync_data()?;
+		std::fs::remove_file(&snapshot)?;
 
-		locked.file.flush()?;
+		if let Some(journal) = &mut locked.journal {
+			journal.clear()?;
+		}
 
 		Ok(())
 	}
 
-	pub fn finish(self) -> Result<(), std::io::Error> { self.flush() }
+	/// Repair the header and offset table of the dataset at `path` if a flush was interrupted while writing them.
+	/// Must be called before the dataset is loaded. Returns if the table was restored.
+	fn restore_table(path: &Path) -> Result<bool, std::io::Error> {
+		let _ = std::fs::remove_file(snapshot_path(path, ".table.tmp"));
+
+		let snapshot = snapshot_path(path, ".table");
+		let table = Self::read_snapshot(path)?;
+		if let Some(table) = &table {
+			let mut file = OpenOptions::new().write(true).open(path)?;
+			file.write_all(table)?;
+			file.sync_data()?;
+		}
+		match std::fs::remove_file(&snapshot) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+			_ => {},
+		}
+
+		Ok(table.is_some())
+	}
+
+	/// The snapshot of the header and offset table left by an interrupted flush, if there is a valid one.
+	fn read_snapshot(path: &Path) -> Result<Option<Vec<u8>>, std::io::Error> {
+		let table = match std::fs::read(snapshot_path(path, ".table")) {
+			Ok(x) => x,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(e) => return Err(e),
+		};
+
+		// The snapshot is renamed into place only once it is complete, so this only fails if it was damaged later.
+		let valid = table.len() >= 32 && table[0..5] == Dataset::MAGIC && {
+			let mut hasher = Hasher::new();
+			hasher.update(&table[0..28]);
+			hasher.update(&table[32..]);
+			hasher.finalize().to_le_bytes() == table[28..32]
+		};
 
-	fn write_to_file(file: &mut File, metadata: TileMetadata, tile_map: &[u64]) -> Result<(), std::io::Error> {
+		Ok(valid.then_some(table))
+	}
+
+	pub fn finish(self) -> Result<(), std::io::Error> {
+		self.flush()?;
+
+		match self.locked.into_inner().unwrap().journal {
+			Some(journal) => journal.remove(),
+			None => Ok(()),
+		}
+	}
+
+	/// The header and offset table, as they are stored at the start of the file.
+	fn encode_table(metadata: TileMetadata, tile_map: &[TileEntry]) -> Vec<u8> {
 		let mut header = [0; 32];
 		header[0..5].copy_from_slice(&Dataset::MAGIC);
 		header[5..7].copy_from_slice(&metadata.version.to_le_bytes());
 		header[7..9].copy_from_slice(&metadata.resolution.to_le_bytes());
 		header[9..11].copy_from_slice(&metadata.height_resolution.to_le_bytes());
+		header[11..13].copy_from_slice(&metadata.tiles_per_degree.to_le_bytes());
+		for (bytes, band) in header[13..21].chunks_exact_mut(2).zip(metadata.width_bands) {
+			bytes[0] = band.latitude;
+			bytes[1] = band.shift;
+		}
 
-		file.write_all(&header)?;
-		file.write_all(unsafe { std::slice::from_raw_parts(tile_map.as_ptr() as _, tile_map.len() * 8) })?;
+		let mut table = Vec::with_capacity(header.len() + tile_map.len() * metadata.entry_size());
+		table.extend_from_slice(&header);
+		for entry in tile_map {
+			entry.write(&mut table);
+		}
 
-		Ok(())
+		let mut hasher = Hasher::new();
+		hasher.update(&table[0..28]);
+		hasher.update(&table[32..]);
+		let checksum = hasher.finalize().to_le_bytes();
+		table[28..32].copy_from_slice(&checksum);
+
+		table
 	}
 
 	fn compress_u8_webp(data: &[u8], width: u32, height: u32) -> Result<Vec<u8>, std::io::Error> {
@@ -190,3 +525,10 @@ impl DatasetBuilder {
 		}
 	}
 }
+
+/// A file next to the dataset at `path`, with `suffix` appended to the name.
+fn snapshot_path(path: &Path, suffix: &str) -> PathBuf {
+	let mut name = path.as_os_str().to_owned();
+	name.push(suffix);
+	PathBuf::from(name)
+}
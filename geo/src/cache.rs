@@ -0,0 +1,268 @@
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		mpsc::{channel, Receiver},
+		Arc,
+		Mutex,
+	},
+};
+
+use crate::{Dataset, DecodeError, DecodedTile, Tile, TileLayers, TileMetadata};
+
+/// Statistics of a [`CachedDataset`].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CacheStats {
+	pub hits: u64,
+	pub misses: u64,
+	/// The number of tiles currently resident.
+	pub tiles: usize,
+	/// The memory used by the resident tiles.
+	pub bytes: usize,
+}
+
+const NIL: usize = usize::MAX;
+
+struct Node {
+	key: (i16, i16),
+	tile: Arc<Tile>,
+	/// The layers that have been decoded into `tile`.
+	layers: TileLayers,
+	/// The next more recently used node.
+	prev: usize,
+	/// The next less recently used node.
+	next: usize,
+}
+
+/// Resident tiles in a doubly linked list from the most to the least recently used, stored in a `Vec` so that lookups,
+/// touches, and evictions are all O(1).
+struct Lru {
+	nodes: Vec<Node>,
+	index: HashMap<(i16, i16), usize>,
+	head: usize,
+	tail: usize,
+	bytes: usize,
+}
+
+impl Lru {
+	fn unlink(&mut self, slot: usize) {
+		let (prev, next) = (self.nodes[slot].prev, self.nodes[slot].next);
+		match prev {
+			NIL => self.head = next,
+			x => self.nodes[x].next = next,
+		}
+		match next {
+			NIL => self.tail = prev,
+			x => self.nodes[x].prev = prev,
+		}
+	}
+
+	fn link_front(&mut self, slot: usize) {
+		self.nodes[slot].prev = NIL;
+		self.nodes[slot].next = self.head;
+		match self.head {
+			NIL => self.tail = slot,
+			x => self.nodes[x].prev = slot,
+		}
+		self.head = slot;
+	}
+
+	fn touch(&mut self, slot: usize) {
+		self.unlink(slot);
+		self.link_front(slot);
+	}
+
+	/// Insert a tile as the most recently used, replacing any tile already resident at `key`.
+	fn insert(&mut self, key: (i16, i16), tile: Arc<Tile>, layers: TileLayers) {
+		if let Some(&slot) = self.index.get(&key) {
+			self.remove(slot);
+		}
+
+		self.bytes += tile.size_in_bytes();
+		self.nodes.push(Node {
+			key,
+			tile,
+			layers,
+			prev: NIL,
+			next: NIL,
+		});
+		let slot = self.nodes.len() - 1;
+		self.index.insert(key, slot);
+		self.link_front(slot);
+	}
+
+	fn remove(&mut self, slot: usize) {
+		self.unlink(slot);
+		let node = self.nodes.swap_remove(slot);
+		self.index.remove(&node.key);
+		self.bytes -= node.tile.size_in_bytes();
+
+		// The last node was moved into the freed slot, so its neighbours need to point to it again.
+		if slot < self.nodes.len() {
+			let (prev, next) = (self.nodes[slot].prev, self.nodes[slot].next);
+			match prev {
+				NIL => self.head = slot,
+				x => self.nodes[x].next = slot,
+			}
+			match next {
+				NIL => self.tail = slot,
+				x => self.nodes[x].prev = slot,
+			}
+			self.index.insert(self.nodes[slot].key, slot);
+		}
+	}
+
+	fn clear(&mut self) {
+		self.nodes.clear();
+		self.index.clear();
+		self.head = NIL;
+		self.tail = NIL;
+		self.bytes = 0;
+	}
+}
+
+/// A [`Dataset`] with a thread-safe, memory-bounded LRU cache of decoded tiles.
+pub struct CachedDataset {
+	dataset: Dataset,
+	capacity: usize,
+	lru: Mutex<Lru>,
+	hits: AtomicU64,
+	misses: AtomicU64,
+}
+
+impl CachedDataset {
+	/// Create a cache that keeps at most `capacity` bytes of decoded tiles resident.
+	pub fn new(dataset: Dataset, capacity: usize) -> Self {
+		Self {
+			dataset,
+			capacity,
+			lru: Mutex::new(Lru {
+				nodes: Vec::new(),
+				index: HashMap::new(),
+				head: NIL,
+				tail: NIL,
+				bytes: 0,
+			}),
+			hits: AtomicU64::new(0),
+			misses: AtomicU64::new(0),
+		}
+	}
+
+	pub fn dataset(&self) -> &Dataset { &self.dataset }
+
+	pub fn metadata(&self) -> TileMetadata { self.dataset.metadata }
+
+	pub fn tile_exists(&self, lat: i16, lon: i16) -> bool { self.dataset.tile_exists(lat, lon) }
+
+	pub fn get_tile(&self, lat: i16, lon: i16) -> Option<Result<(Vec<u16>, Vec<u8>), DecodeError>> {
+		Some(
+			self.get_full_tile(lat, lon)?
+				.map(|tile| (tile.merged_heights(), tile.hillshade.clone())),
+		)
+	}
+
+	pub fn get_full_tile(&self, lat: i16, lon: i16) -> Option<Result<Arc<Tile>, DecodeError>> {
+		self.get_tile_layers(lat, lon, TileLayers::all())
+	}
+
+	/// Same as [`Dataset::get_tile_layers`]. Each tile is cached once, and layers that it doesn't have yet are decoded
+	/// and added to it when they are first requested, so the returned tile can have more layers than `layers`.
+	pub fn get_tile_layers(&self, lat: i16, lon: i16, layers: TileLayers) -> Option<Result<Arc<Tile>, DecodeError>> {
+		tracy::zone!("Get Cached Tile");
+
+		if !self.dataset.tile_exists(lat, lon) {
+			return None;
+		}
+
+		let cached = {
+			let mut lru = self.lru.lock().unwrap();
+			match lru.index.get(&(lat, lon)).copied() {
+				Some(slot) if lru.nodes[slot].layers.contains(layers) => {
+					lru.touch(slot);
+					self.hits.fetch_add(1, Ordering::Relaxed);
+					return Some(Ok(lru.nodes[slot].tile.clone()));
+				},
+				Some(slot) => Some((lru.nodes[slot].tile.clone(), lru.nodes[slot].layers)),
+				None => None,
+			}
+		};
+
+		// Decode without holding the lock, so other threads can hit the cache in the meantime.
+		self.misses.fetch_add(1, Ordering::Relaxed);
+		let missing = cached.as_ref().map_or(layers, |&(_, cached)| layers - cached);
+		let tile = match self.dataset.get_tile_layers(lat, lon, missing)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
+		};
+		let (tile, layers) = match cached {
+			Some((old, cached)) => (Arc::new(merge(&old, tile)), cached | missing),
+			None => (Arc::new(tile), missing),
+		};
+
+		let size = tile.size_in_bytes();
+		if size > self.capacity {
+			return Some(Ok(tile));
+		}
+
+		let mut lru = self.lru.lock().unwrap();
+		if let Some(&slot) = lru.index.get(&(lat, lon)) {
+			lru.remove(slot);
+		}
+		while lru.bytes + size > self.capacity && lru.tail != NIL {
+			let tail = lru.tail;
+			lru.remove(tail);
+		}
+		lru.insert((lat, lon), tile.clone(), layers);
+
+		Some(Ok(tile))
+	}
+
+	/// Same as [`Dataset::decode_tiles`], but goes through the cache.
+	pub fn decode_tiles(
+		self: &Arc<Self>, tiles: &[(i16, i16)], layers: TileLayers,
+	) -> Receiver<DecodedTile<Arc<Tile>>> {
+		let (sender, receiver) = channel();
+		for &(lat, lon) in tiles {
+			let cache = self.clone();
+			let sender = sender.clone();
+			rayon::spawn_fifo(move || {
+				let tile = cache.get_tile_layers(lat, lon, layers);
+				let _ = sender.send(DecodedTile { lat, lon, tile });
+			});
+		}
+
+		receiver
+	}
+
+	/// Drop all resident tiles.
+	pub fn clear(&self) { self.lru.lock().unwrap().clear(); }
+
+	pub fn stats(&self) -> CacheStats {
+		let lru = self.lru.lock().unwrap();
+		CacheStats {
+			hits: self.hits.load(Ordering::Relaxed),
+			misses: self.misses.load(Ordering::Relaxed),
+			tiles: lru.nodes.len(),
+			bytes: lru.bytes,
+		}
+	}
+}
+
+/// Add the layers that were decoded into `new` to the layers of `old`.
+fn merge(old: &Tile, new: Tile) -> Tile {
+	fn pick<T: Clone>(new: Vec<T>, old: &[T]) -> Vec<T> {
+		if new.is_empty() {
+			old.to_vec()
+		} else {
+			new
+		}
+	}
+
+	Tile {
+		width: new.width,
+		height: new.height,
+		heights: pick(new.heights, &old.heights),
+		water: pick(new.water, &old.water),
+		hillshade: pick(new.hillshade, &old.hillshade),
+	}
+}
@@ -1,131 +1,471 @@
-use std::{fs::File, io::Read, path::Path};
+use std::{
+	fs::File,
+	io::Read,
+	ops::Range,
+	path::Path,
+	sync::{
+		mpsc::{channel, Receiver},
+		Arc,
+	},
+};
 
+use crc32fast::Hasher;
 use hcomp::decode::decode;
-use libwebp_sys::WebPDecodeRGBAInto;
+use libwebp_sys::{WebPDecodeRGBAInto, WebPGetInfo};
 use memmap2::{Mmap, MmapOptions};
 
-use crate::{map_lat_lon_to_index, LoadError, TileMetadata, FORMAT_VERSION};
+use crate::{
+	hillshade,
+	map_lat_lon_to_index,
+	DecodeError,
+	Lighting,
+	LoadError,
+	TileEntry,
+	TileMetadata,
+	VerifyError,
+	WidthBand,
+	FORMAT_VERSION,
+	OLDEST_FORMAT_VERSION,
+};
+
+bitflags::bitflags! {
+	/// The layers of a tile to decode.
+	pub struct TileLayers: u32 {
+		const HEIGHT = 1 << 0;
+		const WATER = 1 << 1;
+		const HILLSHADE = 1 << 2;
+	}
+}
+
+/// A decoded tile. Layers that were not requested are empty.
+pub struct Tile {
+	pub width: u32,
+	pub height: u32,
+	/// `height + 500`s in meters.
+	pub heights: Vec<u16>,
+	/// 1 if the pixel is covered by water, 0 otherwise.
+	pub water: Vec<u8>,
+	pub hillshade: Vec<u8>,
+}
+
+impl Tile {
+	/// The memory used by the decoded data.
+	pub fn size_in_bytes(&self) -> usize { self.heights.len() * 2 + self.water.len() + self.hillshade.len() }
+
+	/// Merge the water mask into bit 15 of the heights.
+	pub fn into_merged(self) -> (Vec<u16>, Vec<u8>) { (self.merged_heights(), self.hillshade) }
+
+	/// The heights, with the water mask in bit 15.
+	pub fn merged_heights(&self) -> Vec<u16> {
+		self.heights
+			.iter()
+			.zip(self.water.iter())
+			.map(|(&h, &w)| h | ((w as u16) << 15))
+			.collect()
+	}
+}
+
+/// A tile decoded by [`Dataset::decode_tiles`] or [`CachedDataset::decode_tiles`](crate::CachedDataset::decode_tiles).
+pub struct DecodedTile<T = Tile> {
+	pub lat: i16,
+	pub lon: i16,
+	/// `None` if the tile is not present in the dataset.
+	pub tile: Option<Result<T, DecodeError>>,
+}
 
 pub struct Dataset {
 	pub(crate) metadata: TileMetadata,
-	pub(crate) tile_map: Vec<u64>,
+	pub(crate) tile_map: Vec<TileEntry>,
 	pub(crate) data: Mmap,
 }
 
 impl Dataset {
 	pub(crate) const MAGIC: [u8; 5] = [115, 117, 115, 115, 121];
+	/// Tiles as small as a quarter of a degree. The renderer's tile map is `360 * tiles_per_degree` texels wide, and
+	/// the offset table grows with its square, so this is kept small.
+	pub const MAX_TILES_PER_DEGREE: u16 = 4;
 
 	pub fn load(dir: &Path) -> Result<Self, LoadError> {
 		let meta = std::fs::metadata(&dir)?;
 		if meta.is_dir() {
-			Err(LoadError::UnsupportedFormatVersion)
+			Err(LoadError::DirectoryFormat)
 		} else {
 			let mut file = File::open(dir)?;
-			let mut buffer = Vec::with_capacity(32 + 360 * 180 * 8);
-			buffer.resize(buffer.capacity(), 0);
+			let mut header = [0; 32];
+			file.read_exact(&mut header).map_err(|_| LoadError::InvalidFileSize)?;
 
-			file.read_exact(&mut buffer).map_err(|_| LoadError::InvalidFileSize)?;
+			let metadata = Self::read_metadata(&header)?;
 
-			if buffer[0..5] != Self::MAGIC {
-				return Err(LoadError::InvalidMagic);
-			}
-			let version = u16::from_le_bytes(buffer[5..7].try_into().unwrap());
-			if version != FORMAT_VERSION {
-				return Err(LoadError::UnsupportedFormatVersion);
+			// Check the size before allocating the offset table, which an invalid header could make huge.
+			if file.metadata()?.len() < metadata.header_size() as u64 {
+				return Err(LoadError::InvalidFileSize);
 			}
-			let resolution = u16::from_le_bytes(buffer[7..9].try_into().unwrap());
-			let height_resolution = u16::from_le_bytes(buffer[9..11].try_into().unwrap());
-			let metadata = TileMetadata {
-				version: FORMAT_VERSION,
-				resolution,
-				height_resolution,
-			};
+			let mut buffer = vec![0; metadata.tile_count() * metadata.entry_size()];
+			file.read_exact(&mut buffer).map_err(|_| LoadError::InvalidFileSize)?;
 
-			let tile_map = buffer[32..]
-				.chunks_exact(8)
-				.map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+			if metadata.has_tile_entries() {
+				let expected = u32::from_le_bytes(header[28..32].try_into().unwrap());
+				let mut hasher = Hasher::new();
+				hasher.update(&header[0..28]);
+				hasher.update(&buffer);
+				if hasher.finalize() != expected {
+					return Err(LoadError::InvalidChecksum);
+				}
+			}
+			let tile_map = buffer
+				.chunks_exact(metadata.entry_size())
+				.map(|x| TileEntry::read(&metadata, x))
 				.collect();
 
 			Ok(Dataset {
 				metadata,
 				tile_map,
-				data: unsafe { MmapOptions::new().offset(buffer.len() as _).map(&file)? },
+				data: unsafe { MmapOptions::new().offset(metadata.header_size() as _).map(&file)? },
 			})
 		}
 	}
 
+	/// Parse the metadata in the first 32 bytes of a dataset.
+	pub(crate) fn read_metadata(header: &[u8; 32]) -> Result<TileMetadata, LoadError> {
+		if header[0..5] != Self::MAGIC {
+			return Err(LoadError::InvalidMagic);
+		}
+		let version = u16::from_le_bytes(header[5..7].try_into().unwrap());
+		if !(OLDEST_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+			return Err(LoadError::UnsupportedFormatVersion);
+		}
+		let resolution = u16::from_le_bytes(header[7..9].try_into().unwrap());
+		let height_resolution = u16::from_le_bytes(header[9..11].try_into().unwrap());
+
+		// Versions before 9 always have square, one degree tiles.
+		let tiles_per_degree = if version >= 9 {
+			u16::from_le_bytes(header[11..13].try_into().unwrap())
+		} else {
+			1
+		};
+		if tiles_per_degree == 0 || tiles_per_degree > Self::MAX_TILES_PER_DEGREE {
+			return Err(LoadError::InvalidTileGrid);
+		}
+
+		let mut width_bands = [WidthBand::default(); 4];
+		if version >= 9 {
+			for (band, bytes) in width_bands.iter_mut().zip(header[13..21].chunks_exact(2)) {
+				*band = WidthBand {
+					latitude: bytes[0],
+					shift: bytes[1],
+				};
+			}
+		}
+
+		let metadata = TileMetadata {
+			version,
+			resolution,
+			height_resolution,
+			tiles_per_degree,
+			width_bands,
+		};
+		if !metadata.has_valid_width_bands() {
+			return Err(LoadError::InvalidTileGrid);
+		}
+
+		Ok(metadata)
+	}
+
 	pub fn metadata(&self) -> TileMetadata { self.metadata }
 
 	pub fn tile_exists(&self, lat: i16, lon: i16) -> bool {
-		let index = map_lat_lon_to_index(lat, lon);
-		self.tile_map[index] != 0
+		let index = map_lat_lon_to_index(lat, lon, self.metadata.tiles_per_degree);
+		self.tile_map[index].exists()
 	}
 
-	pub fn tile_count(&self) -> usize { self.tile_map.iter().filter(|&&x| x != 0).count() }
+	pub fn tile_count(&self) -> usize { self.tile_map.iter().filter(|x| x.exists()).count() }
 
-	pub fn get_tile(&self, lat: i16, lon: i16) -> Option<Result<(Vec<u16>, Vec<u8>), std::io::Error>> {
-		Some(match self.get_full_tile(lat, lon)? {
-			Ok((mut data, water, hillshade)) => {
-				for (h, w) in data.iter_mut().zip(water) {
-					*h |= (w as u16) << 15;
-				}
+	/// Where the tile is stored in the file, and the lengths of its layers from format version 9 onwards.
+	pub fn tile_entry(&self, lat: i16, lon: i16) -> Option<TileEntry> {
+		let index = map_lat_lon_to_index(lat, lon, self.metadata.tiles_per_degree);
+		let entry = self.tile_map[index];
+		entry.exists().then_some(entry)
+	}
 
-				Ok((data, hillshade))
-			},
-			Err(e) => Err(e),
-		})
+	pub fn get_tile(&self, lat: i16, lon: i16) -> Option<Result<(Vec<u16>, Vec<u8>), DecodeError>> {
+		Some(self.get_full_tile(lat, lon)?.map(Tile::into_merged))
+	}
+
+	pub fn get_full_tile(&self, lat: i16, lon: i16) -> Option<Result<Tile, DecodeError>> {
+		self.get_tile_layers(lat, lon, TileLayers::all())
 	}
 
-	pub fn get_full_tile(&self, lat: i16, lon: i16) -> Option<Result<(Vec<u16>, Vec<u8>, Vec<u8>), std::io::Error>> {
+	/// Decode only the requested `layers` of the tile. The others are left empty in the returned [`Tile`].
+	pub fn get_tile_layers(&self, lat: i16, lon: i16, layers: TileLayers) -> Option<Result<Tile, DecodeError>> {
 		tracy::zone!("Get Tile");
 
-		let index = map_lat_lon_to_index(lat, lon);
-		let offset = self.tile_map[index] as usize;
-		if offset == 0 {
-			return None;
-		}
+		let entry = self.tile_entry(lat, lon)?;
+		Some(self.decode_tile(entry, lat, layers))
+	}
 
-		let frame = &self.data[offset - (32 + 360 * 180 * 8)..];
-		let res = self.metadata.resolution as u32;
+	fn decode_tile(&self, entry: TileEntry, lat: i16, layers: TileLayers) -> Result<Tile, DecodeError> {
+		let frame = self.frame(entry)?;
+		let width = self.metadata.tile_width(lat) as u32;
+		let height = self.metadata.resolution as u32;
+		let pixels = width as usize * height as usize;
 
-		let (data, len) = {
-			tracy::zone!("Decompress height");
-			match decode(frame, res, res) {
-				Ok(x) => x,
-				Err(e) => return Some(Err(e)),
-			}
+		let (height_frame, water_frame, hillshade_frame) = if self.metadata.has_tile_entries() {
+			let [height_len, water_len, _] = entry.layer_lengths.map(|x| x as usize);
+			let (height_frame, rest) = frame.split_at(height_len);
+			let (water_frame, hillshade_frame) = rest.split_at(water_len);
+			(height_frame, Some(water_frame), Some(hillshade_frame))
+		} else {
+			// Older versions are only delimited by the frames themselves.
+			(frame, None, None)
 		};
-		let data: Vec<_> = {
+
+		// Older versions need the heights to find the other layers, and version 7 generates the hillshade from them.
+		let (data, len) = if layers.contains(TileLayers::HEIGHT) || water_frame.is_none() {
+			let (data, len) = {
+				tracy::zone!("Decompress height");
+				decode(height_frame, width, height).map_err(DecodeError::Hcomp)?
+			};
+			if data.data.len() != pixels || len > height_frame.len() {
+				return Err(DecodeError::Hcomp(std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					"Decoded heightmap has the wrong size",
+				)));
+			}
+
 			tracy::zone!("Unmap height");
-			data.data
+			let data: Vec<_> = data
+				.data
 				.into_owned()
 				.into_iter()
-				.map(|x| x * self.metadata.height_resolution)
-				.collect()
+				.map(|x| x.saturating_mul(self.metadata.height_resolution))
+				.collect();
+			(data, len)
+		} else {
+			(Vec::new(), 0)
 		};
-		let (water, rest) = {
-			tracy::zone!("Decompress water");
 
-			match Self::decompress_u8_webp(&frame[len..], res, res) {
-				Ok(x) => x,
-				Err(e) => return Some(Err(e)),
-			}
+		let (water_frame, rest) = match water_frame {
+			Some(x) => (x, hillshade_frame.unwrap()),
+			None if layers.intersects(TileLayers::WATER | TileLayers::HILLSHADE) => Self::split_webp(&frame[len..])?,
+			None => (&[][..], &[][..]),
 		};
-		let (hillshade, _) = {
+		let water = if layers.contains(TileLayers::WATER) {
+			tracy::zone!("Decompress water");
+			Self::decompress_u8_webp(water_frame, width, height)?
+		} else {
+			Vec::new()
+		};
+		let hillshade = if !layers.contains(TileLayers::HILLSHADE) {
+			Vec::new()
+		} else if self.metadata.version >= 8 {
 			tracy::zone!("Decompress hillshade");
-			match Self::decompress_u8_webp(rest, res, res) {
-				Ok(x) => x,
-				Err(e) => return Some(Err(e)),
+			let hillshade_frame = match hillshade_frame {
+				Some(x) => x,
+				None => Self::split_webp(rest)?.0,
+			};
+			Self::decompress_u8_webp(hillshade_frame, width, height)?
+		} else {
+			tracy::zone!("Generate hillshade");
+			hillshade(&data, width as _, height as _, &Lighting::default())
+		};
+		let data = if layers.contains(TileLayers::HEIGHT) {
+			data
+		} else {
+			Vec::new()
+		};
+
+		Ok(Tile {
+			width,
+			height,
+			heights: data,
+			water,
+			hillshade,
+		})
+	}
+
+	/// Decode `tiles` on the rayon thread pool. Each tile is sent as soon as it has been decoded, so they can arrive in
+	/// any order. Iterating over the receiver blocks until every tile has been received.
+	pub fn decode_tiles(self: &Arc<Self>, tiles: &[(i16, i16)], layers: TileLayers) -> Receiver<DecodedTile> {
+		let (sender, receiver) = channel();
+		for &(lat, lon) in tiles {
+			let dataset = self.clone();
+			let sender = sender.clone();
+			rayon::spawn_fifo(move || {
+				let tile = dataset.get_tile_layers(lat, lon, layers);
+				let _ = sender.send(DecodedTile { lat, lon, tile });
+			});
+		}
+
+		receiver
+	}
+
+	/// Hint to the OS that the tiles covering latitudes in `lat` and longitudes in `lon` (in degrees) will be read
+	/// soon, so it can start paging them in. Latitudes are clamped to the poles, and longitudes wrap around, so `lon`
+	/// can cross the antimeridian, as in `179.0..-179.0`. Does nothing on platforms without `madvise`.
+	pub fn prefetch(&self, lat: Range<f64>, lon: Range<f64>) {
+		tracy::zone!("Prefetch");
+
+		#[cfg(unix)]
+		{
+			if ![lat.start, lat.end, lon.start, lon.end].iter().all(|x| x.is_finite()) {
+				return;
+			}
+
+			let tpd = self.metadata.tiles_per_degree as i32;
+			let lon_tiles = self.metadata.lon_tiles() as i32;
+			let lat = (lat.start * tpd as f64).floor().max(-90.0 * tpd as f64) as i32
+				..(lat.end * tpd as f64).ceil().min(90.0 * tpd as f64) as i32;
+
+			// Measured from the antimeridian, so that the tiles can be counted without wrapping.
+			let start = (lon.start + 180.0).rem_euclid(360.0);
+			let width = (lon.end - lon.start).rem_euclid(360.0);
+			let width = if width == 0.0 && lon.end != lon.start {
+				360.0
+			} else {
+				width
+			};
+			let first = (start * tpd as f64).floor() as i32;
+			let count = (((start + width) * tpd as f64).ceil() as i32 - first).min(lon_tiles);
+
+			let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+			let base = self.data.as_ptr() as usize;
+			for lat in lat {
+				for lon in (first..first + count).map(|x| x.rem_euclid(lon_tiles) - 180 * tpd) {
+					let (lat, lon) = (lat as i16, lon as i16);
+					let data = match self.raw_tile(lat, lon) {
+						Some(Ok(x)) => x,
+						_ => continue,
+					};
+					// Older versions don't store the length of a tile, so only the start can be prefetched.
+					let len = if self.metadata.has_tile_entries() {
+						data.len()
+					} else {
+						data.len().min(page)
+					};
+
+					// The mapping starts on a page boundary before `base`, so aligning down stays inside it.
+					let start = data.as_ptr() as usize;
+					let aligned = (start & !(page - 1)).max(base & !(page - 1));
+					unsafe {
+						libc::madvise(aligned as _, start + len - aligned, libc::MADV_WILLNEED);
+					}
+				}
 			}
+		}
+
+		#[cfg(not(unix))]
+		let _ = (lat, lon);
+	}
+
+	/// The encoded tile data, split into layers by [`TileEntry::layer_lengths`]. Before format version 9, the length
+	/// of a tile is not stored, so this extends until the end of the file.
+	pub fn raw_tile(&self, lat: i16, lon: i16) -> Option<Result<&[u8], DecodeError>> {
+		let entry = self.tile_entry(lat, lon)?;
+		Some(self.frame(entry))
+	}
+
+	/// The encoded height, water, and hillshade layers of the tile, so they can be copied without encoding them again.
+	/// Before format version 9, layers are only delimited by the frames themselves, so the heights are decoded to find
+	/// where they end. Version 7 doesn't store the hillshade, so that layer is empty.
+	pub fn raw_layers(&self, lat: i16, lon: i16) -> Option<Result<[&[u8]; 3], DecodeError>> {
+		let entry = self.tile_entry(lat, lon)?;
+		Some(self.split_layers(entry, lat))
+	}
+
+	fn split_layers(&self, entry: TileEntry, lat: i16) -> Result<[&[u8]; 3], DecodeError> {
+		let frame = self.frame(entry)?;
+		if self.metadata.has_tile_entries() {
+			let [height_len, water_len, _] = entry.layer_lengths.map(|x| x as usize);
+			let (height_frame, rest) = frame.split_at(height_len);
+			let (water_frame, hillshade_frame) = rest.split_at(water_len);
+			return Ok([height_frame, water_frame, hillshade_frame]);
+		}
+
+		let len = {
+			tracy::zone!("Decompress height");
+			let width = self.metadata.tile_width(lat) as u32;
+			decode(frame, width, self.metadata.resolution as u32)
+				.map_err(DecodeError::Hcomp)?
+				.1
+		};
+		if len > frame.len() {
+			return Err(DecodeError::TruncatedFrame);
+		}
+
+		let (height_frame, rest) = frame.split_at(len);
+		let (water_frame, rest) = Self::split_webp(rest)?;
+		let hillshade_frame = if self.metadata.version >= 8 {
+			Self::split_webp(rest)?.0
+		} else {
+			&[]
+		};
+
+		Ok([height_frame, water_frame, hillshade_frame])
+	}
+
+	/// Check the tile against its stored checksum. Versions without checksums only check that the tile is in bounds.
+	pub fn verify_tile(&self, lat: i16, lon: i16) -> Option<Result<(), VerifyError>> {
+		tracy::zone!("Verify Tile");
+
+		let entry = self.tile_entry(lat, lon)?;
+		let data = match self.frame(entry) {
+			Ok(x) => x,
+			Err(_) => return Some(Err(VerifyError::Truncated)),
 		};
 
-		Some(Ok((data, water, hillshade)))
+		if !self.metadata.has_tile_entries() {
+			return Some(Ok(()));
+		}
+
+		let expected = entry.checksum;
+
+		let actual = crc32fast::hash(data);
+		Some(if actual == expected {
+			Ok(())
+		} else {
+			Err(VerifyError::ChecksumMismatch { expected, actual })
+		})
+	}
+
+	/// The tile data of `entry`. Before format version 9, this extends until the end of the file.
+	fn frame(&self, entry: TileEntry) -> Result<&[u8], DecodeError> {
+		let start = (entry.offset as usize)
+			.checked_sub(self.metadata.header_size())
+			.filter(|&x| x < self.data.len())
+			.ok_or(DecodeError::BadOffset)?;
+
+		if self.metadata.has_tile_entries() {
+			let end = start + entry.data_len() as usize;
+			self.data.get(start..end).ok_or(DecodeError::TruncatedFrame)
+		} else {
+			Ok(&self.data[start..])
+		}
 	}
 
-	fn decompress_u8_webp(data: &[u8], width: u32, height: u32) -> Result<(Vec<u8>, &[u8]), std::io::Error> {
+	/// Split a webp frame off the start of `data`, using the size in its RIFF header.
+	fn split_webp(data: &[u8]) -> Result<(&[u8], &[u8]), DecodeError> {
+		// The four byte tag, followed by the size of the rest of the frame.
+		let frame_size = data
+			.get(4..8)
+			.map(|x| u32::from_le_bytes(x.try_into().unwrap()) as usize + 8)
+			.ok_or(DecodeError::TruncatedFrame)?;
+		if frame_size > data.len() {
+			return Err(DecodeError::TruncatedFrame);
+		}
+
+		Ok(data.split_at(frame_size))
+	}
+
+	fn decompress_u8_webp(frame: &[u8], width: u32, height: u32) -> Result<Vec<u8>, DecodeError> {
 		unsafe {
-			let frame_size = u32::from_le_bytes(data[4..8].try_into().unwrap()) + 8;
-			let frame = &data[..frame_size as usize];
+			let (mut frame_width, mut frame_height) = (0, 0);
+			if WebPGetInfo(frame.as_ptr(), frame.len(), &mut frame_width, &mut frame_height) == 0
+				|| frame_width != width as i32 / 2
+				|| frame_height != height as i32 / 2
+			{
+				return Err(DecodeError::Webp);
+			}
+
 			let mut decompressed = vec![0; width as usize * height as usize];
 			if WebPDecodeRGBAInto(
 				frame.as_ptr(),
@@ -136,13 +476,10 @@ impl Dataset {
 			)
 			.is_null()
 			{
-				return Err(std::io::Error::new(
-					std::io::ErrorKind::Other,
-					"WebPDecodeRGBAInto failed",
-				));
+				return Err(DecodeError::Webp);
 			}
 
-			Ok((decompressed, &data[frame_size as usize..]))
+			Ok(decompressed)
 		}
 	}
 }
@@ -0,0 +1,35 @@
+/// The mean radius of the earth, in meters.
+pub const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// The point `distance` meters from `from` along the great circle with the initial `bearing` (in radians, clockwise
+/// from north). Points are `(lat, lon)` in degrees.
+pub fn great_circle_destination(from: (f64, f64), bearing: f64, distance: f64) -> (f64, f64) {
+	let (lat, lon) = (from.0.to_radians(), from.1.to_radians());
+	let angle = distance / EARTH_RADIUS;
+
+	let dest_lat = (lat.sin() * angle.cos() + lat.cos() * angle.sin() * bearing.cos()).asin();
+	let dest_lon = lon + (bearing.sin() * angle.sin() * lat.cos()).atan2(angle.cos() - lat.sin() * dest_lat.sin());
+
+	(
+		dest_lat.to_degrees(),
+		(dest_lon.to_degrees() + 180.0).rem_euclid(360.0) - 180.0,
+	)
+}
+
+/// The length of the great circle between two points, in meters.
+pub fn great_circle_distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+	let (lat1, lat2) = (from.0.to_radians(), to.0.to_radians());
+	let dlat = lat2 - lat1;
+	let dlon = (to.1 - from.1).to_radians();
+
+	let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+	2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+}
+
+/// The bearing at `from` of the great circle to `to`, in radians clockwise from north.
+pub fn initial_bearing(from: (f64, f64), to: (f64, f64)) -> f64 {
+	let (lat1, lat2) = (from.0.to_radians(), to.0.to_radians());
+	let dlon = (to.1 - from.1).to_radians();
+
+	(dlon.sin() * lat2.cos()).atan2(lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos())
+}
@@ -0,0 +1,108 @@
+/// The light a hillshade is generated with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Lighting {
+	/// The direction the light comes from, in degrees clockwise from north.
+	pub azimuth: f32,
+	/// The angle between the light and straight up, in degrees.
+	pub zenith: f32,
+	/// Blend light from four directions around `azimuth`, so slopes facing away from it still show relief.
+	pub multi_directional: bool,
+}
+
+impl Default for Lighting {
+	fn default() -> Self {
+		Self {
+			azimuth: 315.0,
+			zenith: 45.0,
+			multi_directional: false,
+		}
+	}
+}
+
+impl Lighting {
+	/// The offsets from `azimuth` of the directions blended in multi-directional mode.
+	pub const MULTI_DIRECTIONAL_OFFSETS: [f32; 4] = [-67.5, -22.5, 22.5, 67.5];
+
+	/// The brightness, from 0 to 1, of a surface with the gradient `dzdx`, `dzdy`, where y points south.
+	pub fn shade(&self, dzdx: f32, dzdy: f32) -> f32 {
+		let slope = (dzdx * dzdx + dzdy * dzdy).sqrt().atan();
+		let aspect = if dzdx != 0.0 {
+			let aspect = dzdy.atan2(-dzdx);
+			if aspect < 0.0 {
+				aspect + 2.0 * std::f32::consts::PI
+			} else {
+				aspect
+			}
+		} else if dzdy > 0.0 {
+			0.5 * std::f32::consts::PI
+		} else {
+			1.5 * std::f32::consts::PI
+		};
+
+		let zenith = self.zenith.to_radians();
+		let light = |azimuth: f32| {
+			// Aspects are counter-clockwise from east.
+			let azimuth = (450.0 - azimuth).to_radians();
+			(zenith.cos() * slope.cos() + zenith.sin() * slope.sin() * (azimuth - aspect).cos()).clamp(0.0, 1.0)
+		};
+
+		if self.multi_directional {
+			Self::MULTI_DIRECTIONAL_OFFSETS
+				.iter()
+				.map(|offset| light(self.azimuth + offset))
+				.sum::<f32>()
+				/ Self::MULTI_DIRECTIONAL_OFFSETS.len() as f32
+		} else {
+			light(self.azimuth)
+		}
+	}
+}
+
+/// Generate a hillshade for a `width` * `height` heightmap, lit by `lighting`.
+///
+/// Pixels on the border use their nearest neighbours inside the tile.
+pub fn hillshade(heights: &[u16], width: usize, height: usize, lighting: &Lighting) -> Vec<u8> {
+	shade_pixels(width, height, lighting, |x, y, dx, dy| {
+		let x = (x as isize + dx).clamp(0, width as isize - 1) as usize;
+		let y = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+		heights[y * width + x] as f32
+	})
+}
+
+/// Generate a hillshade for the `width` * `height` inside of a heightmap that has an extra pixel on every side, so
+/// pixels on the border of the tile are shaded with their real neighbours.
+pub fn hillshade_with_border(heights: &[u16], width: usize, height: usize, lighting: &Lighting) -> Vec<u8> {
+	let stride = width + 2;
+	shade_pixels(width, height, lighting, |x, y, dx, dy| {
+		let x = (x as isize + 1 + dx) as usize;
+		let y = (y as isize + 1 + dy) as usize;
+		heights[y * stride + x] as f32
+	})
+}
+
+/// Shade each pixel from the Sobel gradient of its neighbours. `sample(x, y, dx, dy)` returns the height of the
+/// neighbour at `dx`, `dy` from `x`, `y`.
+fn shade_pixels(
+	width: usize, height: usize, lighting: &Lighting, sample: impl Fn(usize, usize, isize, isize) -> f32,
+) -> Vec<u8> {
+	let mut out = vec![0; width * height];
+	for y in 0..height {
+		for x in 0..width {
+			let a = sample(x, y, -1, -1);
+			let b = sample(x, y, 0, -1);
+			let c = sample(x, y, 1, -1);
+			let d = sample(x, y, -1, 0);
+			let f = sample(x, y, 1, 0);
+			let g = sample(x, y, -1, 1);
+			let h = sample(x, y, 0, 1);
+			let i = sample(x, y, 1, 1);
+
+			let dzdx = ((c + 2.0 * f + i) - (a + 2.0 * d + g)) / 8.0;
+			let dzdy = ((g + 2.0 * h + i) - (a + 2.0 * b + c)) / 8.0;
+
+			out[y * width + x] = (lighting.shade(dzdx, dzdy) * 255.0).round() as u8;
+		}
+	}
+
+	out
+}
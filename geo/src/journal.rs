@@ -0,0 +1,98 @@
+use std::{
+	fs::{File, OpenOptions},
+	io::{Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+};
+
+use crate::TileEntry;
+
+/// What was recovered when a [`DatasetBuilder`](crate::DatasetBuilder) was resumed with a journal.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct JournalRecovery {
+	/// If the header and offset table were restored after a flush was interrupted while writing them.
+	pub table_restored: bool,
+	/// The number of tiles that were added after the last flush, and recovered from the journal.
+	pub tiles: usize,
+	/// The number of unreferenced bytes at the end of the file that were removed.
+	pub reclaimed_bytes: u64,
+}
+
+/// An append-only log of the tiles written since the offset table was last flushed.
+///
+/// Each record is 36 bytes:
+/// * [0..8]: The index of the tile.
+/// * [8..16]: The offset of the tile.
+/// * [16..28]: The lengths of the height, water, and hillshade layers.
+/// * [28..32]: The CRC-32 of the tile data.
+/// * [32..36]: The CRC-32 of [0..32].
+pub(crate) struct Journal {
+	path: PathBuf,
+	file: File,
+}
+
+impl Journal {
+	const RECORD_SIZE: usize = 36;
+
+	/// The journal of the dataset at `path` is stored next to it, with `.journal` appended to the name.
+	pub fn open(path: &Path) -> Result<Self, std::io::Error> {
+		let mut name = path.as_os_str().to_owned();
+		name.push(".journal");
+		let path = PathBuf::from(name);
+
+		Ok(Self {
+			file: OpenOptions::new().read(true).append(true).create(true).open(&path)?,
+			path,
+		})
+	}
+
+	/// Read every complete record, stopping at the first torn or corrupt one.
+	pub fn replay(&mut self) -> Result<Vec<(usize, TileEntry)>, std::io::Error> {
+		let mut buffer = Vec::new();
+		self.file.seek(SeekFrom::Start(0))?;
+		self.file.read_to_end(&mut buffer)?;
+
+		let u32_at = |record: &[u8], i: usize| u32::from_le_bytes(record[i..i + 4].try_into().unwrap());
+		let u64_at = |record: &[u8], i: usize| u64::from_le_bytes(record[i..i + 8].try_into().unwrap());
+
+		Ok(buffer
+			.chunks_exact(Self::RECORD_SIZE)
+			.take_while(|record| crc32fast::hash(&record[0..32]) == u32_at(record, 32))
+			.map(|record| {
+				(
+					u64_at(record, 0) as usize,
+					TileEntry {
+						offset: u64_at(record, 8),
+						layer_lengths: [u32_at(record, 16), u32_at(record, 20), u32_at(record, 24)],
+						checksum: u32_at(record, 28),
+					},
+				)
+			})
+			.collect())
+	}
+
+	/// Durably record tiles. The tile data must already be synced to disk.
+	pub fn append(&mut self, records: &[(usize, TileEntry)]) -> Result<(), std::io::Error> {
+		let mut buffer = Vec::with_capacity(records.len() * Self::RECORD_SIZE);
+		for (index, entry) in records {
+			let start = buffer.len();
+			buffer.extend_from_slice(&(*index as u64).to_le_bytes());
+			entry.write(&mut buffer);
+			let checksum = crc32fast::hash(&buffer[start..]);
+			buffer.extend_from_slice(&checksum.to_le_bytes());
+		}
+
+		self.file.write_all(&buffer)?;
+		self.file.sync_data()
+	}
+
+	/// Forget all records, once the offset table that contains them has been synced to disk.
+	pub fn clear(&mut self) -> Result<(), std::io::Error> {
+		self.file.set_len(0)?;
+		self.file.sync_data()
+	}
+
+	pub fn remove(self) -> Result<(), std::io::Error> {
+		drop(self.file);
+		std::fs::remove_file(self.path)
+	}
+}
@@ -9,8 +9,22 @@ use std::{
 
 mod dataset;
 pub use dataset::*;
+mod geodesy;
+pub use geodesy::*;
 mod builder;
 pub use builder::*;
+mod cache;
+pub use cache::*;
+mod hillshade;
+pub use hillshade::*;
+mod journal;
+pub use journal::*;
+mod order;
+pub use order::*;
+mod query;
+pub use query::*;
+mod taws;
+pub use taws::*;
 
 /// ## Format version 1
 /// Metadata file (_meta):
@@ -139,12 +153,50 @@ pub use builder::*;
 ///   beginning of the file). If zero, the tile is not present.
 /// * [offset..]: A hcomp frame containing the compressed data of the tile, until the next tile, followed by a webp
 ///   image of the water mask, further followed by a webp image of the hillshade.
-pub const FORMAT_VERSION: u16 = 8;
+///
+/// # Format version 9
+/// Tiles can span less than a degree, narrow towards the poles, and are checksummed. The offset table stores the
+/// length of each layer, so tiles can be read with a single bounded read.
+/// * [0..5]: Magic number: `[115, 117, 115, 115, 121]`.
+/// * [5..7]: The format version, little endian.
+/// * [7..9]: The resolution of the tile (its height, and the width of tiles near the equator).
+/// * [9..11]: The resolution of height values (round each raw value to the nearest multiple).
+/// * [11..13] @ tpd: The number of tiles per degree, along both latitude and longitude, from 1 to 4. Each tile spans `1
+///   / tpd` degrees.
+/// * [13..21]: 4 width bands, each being a `u8` latitude in degrees followed by a `u8` shift. A tile whose edge closest
+///   to the equator is at or above the latitude of a band has a width of `resolution >> shift`. If multiple bands
+///   apply, the largest shift is used. Unused bands have a shift of 0.
+/// * [21..28]: Empty space, for future use. Must be 0.
+/// * [28..32]: The CRC-32 of [0..28] followed by the offset table.
+/// * [32..32 + 360 * tpd * 180 * tpd * 24] @ entries: 360 * tpd * 180 * tpd entries of 24 bytes each:
+///   * [0..8] @ offset: The offset of the tile in question (from the beginning of the file). If zero, the tile is not
+///     present.
+///   * [8..12] @ hlen: The length of the height layer.
+///   * [12..16] @ wlen: The length of the water layer.
+///   * [16..20] @ slen: The length of the hillshade layer.
+///   * [20..24]: The CRC-32 of the tile data.
+/// * [offset..offset + hlen]: A hcomp frame containing the compressed heights.
+/// * [offset + hlen..offset + hlen + wlen]: A webp image of the water mask.
+/// * [offset + hlen + wlen..offset + hlen + wlen + slen]: A webp image of the hillshade.
+///
+/// Tiles are addressed by the latitude and longitude of their bottom-left corner, in units of `1 / tpd` degrees.
+///
+/// # Compatibility
+/// Datasets from format version 7 onwards can be read. Version 7 tiles do not store a hillshade, so it is generated
+/// when the tile is decoded.
+pub const FORMAT_VERSION: u16 = 9;
+/// The oldest format version that can still be read.
+pub const OLDEST_FORMAT_VERSION: u16 = 7;
 
 pub enum LoadError {
 	InvalidFileSize,
 	InvalidMagic,
 	UnsupportedFormatVersion,
+	DirectoryFormat,
+	InvalidChecksum,
+	InvalidTileGrid,
+	/// The dataset is not the one that was expected, such as when it was built with different settings.
+	MetadataMismatch,
 	Io(std::io::Error),
 }
 
@@ -154,6 +206,10 @@ impl Display for LoadError {
 			Self::InvalidFileSize => write!(f, "Invalid file size"),
 			Self::InvalidMagic => write!(f, "Invalid magic number"),
 			Self::UnsupportedFormatVersion => write!(f, "Unknown format version"),
+			Self::DirectoryFormat => write!(f, "Directory datasets (format versions 1 and 2) are unsupported"),
+			Self::InvalidChecksum => write!(f, "Header checksum mismatch"),
+			Self::InvalidTileGrid => write!(f, "Invalid number of tiles per degree"),
+			Self::MetadataMismatch => write!(f, "Dataset has different metadata"),
 			Self::Io(x) => write!(f, "IO error: {}", x),
 		}
 	}
@@ -169,30 +225,199 @@ impl From<std::io::Error> for LoadError {
 	fn from(x: std::io::Error) -> Self { Self::Io(x) }
 }
 
+pub enum DecodeError {
+	/// The offset of the tile does not point into the file.
+	BadOffset,
+	/// A frame of the tile extends past the end of the tile data.
+	TruncatedFrame,
+	/// The height frame could not be decoded.
+	Hcomp(std::io::Error),
+	/// A webp frame could not be decoded, or has the wrong dimensions.
+	Webp,
+}
+
+impl Display for DecodeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Self::BadOffset => write!(f, "Tile offset is out of bounds"),
+			Self::TruncatedFrame => write!(f, "Tile frame is truncated"),
+			Self::Hcomp(x) => write!(f, "Height decoding error: {}", x),
+			Self::Webp => write!(f, "WebP decoding error"),
+		}
+	}
+}
+
+impl Debug for DecodeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { Display::fmt(self, f) }
+}
+
+impl Error for DecodeError {}
+
+pub enum VerifyError {
+	/// The tile extends past the end of the file.
+	Truncated,
+	/// The stored checksum does not match the tile data.
+	ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl Display for VerifyError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Self::Truncated => write!(f, "Tile is truncated"),
+			Self::ChecksumMismatch { expected, actual } => {
+				write!(
+					f,
+					"Checksum mismatch: expected {:#010x}, got {:#010x}",
+					expected, actual
+				)
+			},
+		}
+	}
+}
+
+impl Debug for VerifyError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { Display::fmt(self, f) }
+}
+
+impl Error for VerifyError {}
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 #[repr(C)]
 pub struct TileMetadata {
 	/// The file format version.
 	pub version: u16,
-	/// The length of the side of the square tile.
+	/// The height of each tile, and the width of tiles that are not narrowed by a width band.
 	pub resolution: u16,
 	/// The multiplier for the raw stored values.
 	pub height_resolution: u16,
+	/// The number of tiles along one degree of latitude or longitude.
+	pub tiles_per_degree: u16,
+	/// Latitude bands with narrower tiles.
+	pub width_bands: [WidthBand; 4],
+}
+
+/// Narrows tiles at or above `latitude` (north or south) to a width of `resolution >> shift`.
+#[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+pub struct WidthBand {
+	/// The absolute latitude the band starts at, in degrees.
+	pub latitude: u8,
+	pub shift: u8,
+}
+
+/// The location of a tile in the file.
+#[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+pub struct TileEntry {
+	/// The offset from the beginning of the file. Zero if the tile is not present.
+	pub offset: u64,
+	/// The lengths of the height, water, and hillshade layers. Zero before format version 9.
+	pub layer_lengths: [u32; 3],
+	/// The CRC-32 of the tile data. Zero before format version 9.
+	pub checksum: u32,
+}
+
+impl TileEntry {
+	/// The total length of the tile data.
+	pub fn data_len(&self) -> u64 { self.layer_lengths.iter().map(|&x| x as u64).sum() }
+
+	pub fn exists(&self) -> bool { self.offset != 0 }
+
+	pub(crate) fn read(metadata: &TileMetadata, bytes: &[u8]) -> Self {
+		let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+
+		let offset = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+		if metadata.has_tile_entries() {
+			Self {
+				offset,
+				layer_lengths: [u32_at(8), u32_at(12), u32_at(16)],
+				checksum: u32_at(20),
+			}
+		} else {
+			Self {
+				offset,
+				..Default::default()
+			}
+		}
+	}
+
+	pub(crate) fn write(&self, out: &mut Vec<u8>) {
+		out.extend_from_slice(&self.offset.to_le_bytes());
+		for length in self.layer_lengths {
+			out.extend_from_slice(&length.to_le_bytes());
+		}
+		out.extend_from_slice(&self.checksum.to_le_bytes());
+	}
+}
+
+impl TileMetadata {
+	/// The number of tiles in the grid.
+	pub fn tile_count(&self) -> usize { 360 * 180 * self.tiles_per_degree as usize * self.tiles_per_degree as usize }
+
+	/// The number of tiles along a parallel.
+	pub fn lon_tiles(&self) -> usize { 360 * self.tiles_per_degree as usize }
+
+	/// The number of tiles along a meridian.
+	pub fn lat_tiles(&self) -> usize { 180 * self.tiles_per_degree as usize }
+
+	/// The size of the side of a tile, in degrees.
+	pub fn tile_span(&self) -> f64 { 1.0 / self.tiles_per_degree as f64 }
+
+	/// The width of the tiles in the row at `lat`.
+	pub fn tile_width(&self, lat: i16) -> u16 {
+		// The edge of the tile closest to the equator.
+		let lat = if lat >= 0 { lat as f64 } else { -(lat as f64 + 1.0) };
+		let lat = lat * self.tile_span();
+		let shift = self
+			.width_bands
+			.iter()
+			.filter(|band| lat >= band.latitude as f64)
+			.map(|band| band.shift)
+			.max()
+			.unwrap_or(0);
+
+		self.resolution >> shift
+	}
+
+	/// Every band must leave tiles with a non-zero, even width, since the water mask and hillshade are packed in pairs.
+	pub fn has_valid_width_bands(&self) -> bool {
+		self.width_bands
+			.iter()
+			.all(|band| band.shift < 15 && self.resolution % (2 << band.shift) == 0)
+	}
+
+	/// If the offset table stores the length of each layer and the checksum of each tile, and the header is
+	/// checksummed. Older versions only store the offset of each tile.
+	pub fn has_tile_entries(&self) -> bool { self.version >= 9 }
+
+	/// The size of each entry in the offset table, in bytes.
+	pub(crate) fn entry_size(&self) -> usize {
+		if self.has_tile_entries() {
+			24
+		} else {
+			8
+		}
+	}
+
+	/// The size of the header and the offset table, in bytes.
+	pub(crate) fn header_size(&self) -> usize { 32 + self.tile_count() * self.entry_size() }
 }
 
-pub fn map_lat_lon_to_index(lat: i16, lon: i16) -> usize {
-	debug_assert!(lat >= -90 && lat < 90, "Latitude out of range");
-	debug_assert!(lon >= -180 && lon < 180, "Longitude out of range");
+/// `lat` and `lon` are in units of `1 / tiles_per_degree` degrees.
+pub fn map_lat_lon_to_index(lat: i16, lon: i16, tiles_per_degree: u16) -> usize {
+	let tpd = tiles_per_degree as i32;
+	let (lat, lon) = (lat as i32, lon as i32);
+	debug_assert!(lat >= -90 * tpd && lat < 90 * tpd, "Latitude out of range");
+	debug_assert!(lon >= -180 * tpd && lon < 180 * tpd, "Longitude out of range");
 
-	let lat = (lat + 90) as usize;
-	let lon = (lon + 180) as usize;
-	lat * 360 + lon
+	let lat = (lat + 90 * tpd) as usize;
+	let lon = (lon + 180 * tpd) as usize;
+	lat * 360 * tpd as usize + lon
 }
 
-pub fn map_index_to_lat_lon(index: usize) -> (i16, i16) {
-	debug_assert!(index < 180 * 360, "Index out of range");
+pub fn map_index_to_lat_lon(index: usize, tiles_per_degree: u16) -> (i16, i16) {
+	let tpd = tiles_per_degree as usize;
+	debug_assert!(index < 180 * 360 * tpd * tpd, "Index out of range");
 
-	let lat = (index / 360) as i16 - 90;
-	let lon = (index % 360) as i16 - 180;
+	let lat = (index / (360 * tpd)) as i16 - 90 * tpd as i16;
+	let lon = (index % (360 * tpd)) as i16 - 180 * tpd as i16;
 	(lat, lon)
 }
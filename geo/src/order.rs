@@ -0,0 +1,70 @@
+use crate::TileMetadata;
+
+/// An order to lay out tiles in a file.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TileOrder {
+	/// Row by row from the south pole, the same as the offset table.
+	RowMajor,
+	/// Along a Hilbert curve, which keeps neighbouring tiles close together.
+	Hilbert,
+	/// Along a Z-order (Morton) curve.
+	ZOrder,
+}
+
+impl TileOrder {
+	/// Sort tile `indices` (into the offset table of `metadata`) into this order.
+	pub fn sort(self, metadata: &TileMetadata, indices: &mut [usize]) {
+		match self {
+			Self::RowMajor => indices.sort_unstable(),
+			_ => indices.sort_by_cached_key(|&index| self.key(metadata, index)),
+		}
+	}
+
+	/// The position of the tile at `index` (into the offset table of `metadata`) along this order.
+	pub fn key(self, metadata: &TileMetadata, index: usize) -> u64 {
+		let lon_tiles = metadata.lon_tiles();
+		let (x, y) = ((index % lon_tiles) as u64, (index / lon_tiles) as u64);
+
+		match self {
+			Self::RowMajor => index as u64,
+			// The curve covers a square with a power of two side.
+			Self::Hilbert => hilbert(lon_tiles.next_power_of_two() as u64, x, y),
+			Self::ZOrder => spread(x) | (spread(y) << 1),
+		}
+	}
+}
+
+/// The distance of `x`, `y` along a Hilbert curve filling a `side` * `side` square.
+fn hilbert(side: u64, mut x: u64, mut y: u64) -> u64 {
+	let mut d = 0;
+	let mut s = side / 2;
+	while s > 0 {
+		let rx = (x & s != 0) as u64;
+		let ry = (y & s != 0) as u64;
+		d += s * s * ((3 * rx) ^ ry);
+
+		// Rotate the quadrant so the curve stays continuous.
+		if ry == 0 {
+			if rx == 1 {
+				x = side - 1 - x;
+				y = side - 1 - y;
+			}
+			std::mem::swap(&mut x, &mut y);
+		}
+
+		s /= 2;
+	}
+
+	d
+}
+
+/// Insert a zero bit above each of the low 32 bits of `x`.
+fn spread(x: u64) -> u64 {
+	let mut x = x & 0xffff_ffff;
+	x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+	x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+	x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+	x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+	x = (x | (x << 1)) & 0x5555_5555_5555_5555;
+	x
+}
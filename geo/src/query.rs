@@ -0,0 +1,184 @@
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Arc,
+};
+
+use crate::{CachedDataset, Dataset, DecodeError, Tile, TileLayers, TileMetadata};
+
+/// Queries never need the hillshade.
+const LAYERS: TileLayers = TileLayers::HEIGHT.union(TileLayers::WATER);
+
+/// The terrain at a point on the globe.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Elevation {
+	/// Height above sea level, in meters.
+	pub height: f32,
+	/// If the point is covered by water.
+	pub water: bool,
+}
+
+impl Dataset {
+	/// Get the bilinearly interpolated terrain at `lat`, `lon` (in degrees).
+	///
+	/// Samples are taken across tile borders, and missing tiles are treated as water at sea level.
+	pub fn elevation_at(&self, lat: f64, lon: f64) -> Result<Elevation, DecodeError> {
+		tracy::zone!("Elevation Query");
+
+		Sampler::new(self.metadata, |lat, lon| {
+			self.get_tile_layers(lat, lon, LAYERS)
+				.transpose()
+				.map(|x| x.map(Arc::new))
+		})
+		.sample(lat, lon)
+	}
+
+	/// Get the terrain at each `(lat, lon)` pair. Tiles are only decoded once per call, so nearby points are cheap.
+	pub fn elevations_at(&self, points: &[(f64, f64)]) -> Result<Vec<Elevation>, DecodeError> {
+		tracy::zone!("Batch Elevation Query");
+
+		let mut sampler = Sampler::new(self.metadata, |lat, lon| {
+			self.get_tile_layers(lat, lon, LAYERS)
+				.transpose()
+				.map(|x| x.map(Arc::new))
+		});
+		points.iter().map(|&(lat, lon)| sampler.sample(lat, lon)).collect()
+	}
+
+	/// Same as [`elevations_at`](Self::elevations_at), but a point is `None` if a tile it needs could not be decoded,
+	/// instead of failing the whole query.
+	pub(crate) fn elevations_or_unknown(&self, points: &[(f64, f64)]) -> Vec<Option<Elevation>> {
+		tracy::zone!("Batch Elevation Query");
+
+		// Failed tiles aren't cached by the sampler, so they are remembered here to only be decoded once.
+		let mut failed = HashSet::new();
+		let mut sampler = Sampler::new(self.metadata, |lat, lon| {
+			if failed.contains(&(lat, lon)) {
+				return Err(());
+			}
+			self.get_tile_layers(lat, lon, LAYERS)
+				.transpose()
+				.map(|x| x.map(Arc::new))
+				.map_err(|_| {
+					failed.insert((lat, lon));
+				})
+		});
+		points.iter().map(|&(lat, lon)| sampler.sample(lat, lon).ok()).collect()
+	}
+}
+
+impl CachedDataset {
+	/// Same as [`Dataset::elevation_at`], but goes through the cache.
+	pub fn elevation_at(&self, lat: f64, lon: f64) -> Result<Elevation, DecodeError> {
+		tracy::zone!("Elevation Query");
+
+		Sampler::new(self.metadata(), |lat, lon| {
+			self.get_tile_layers(lat, lon, LAYERS).transpose()
+		})
+		.sample(lat, lon)
+	}
+
+	/// Same as [`Dataset::elevations_at`], but goes through the cache.
+	pub fn elevations_at(&self, points: &[(f64, f64)]) -> Result<Vec<Elevation>, DecodeError> {
+		tracy::zone!("Batch Elevation Query");
+
+		let mut sampler = Sampler::new(self.metadata(), |lat, lon| {
+			self.get_tile_layers(lat, lon, LAYERS).transpose()
+		});
+		points.iter().map(|&(lat, lon)| sampler.sample(lat, lon)).collect()
+	}
+
+	/// Same as [`Dataset::elevations_or_unknown`], but goes through the cache.
+	pub(crate) fn elevations_or_unknown(&self, points: &[(f64, f64)]) -> Vec<Option<Elevation>> {
+		tracy::zone!("Batch Elevation Query");
+
+		let mut failed = HashSet::new();
+		let mut sampler = Sampler::new(self.metadata(), |lat, lon| {
+			if failed.contains(&(lat, lon)) {
+				return Err(());
+			}
+			self.get_tile_layers(lat, lon, LAYERS).transpose().map_err(|_| {
+				failed.insert((lat, lon));
+			})
+		});
+		points.iter().map(|&(lat, lon)| sampler.sample(lat, lon).ok()).collect()
+	}
+}
+
+struct Sampler<F> {
+	metadata: TileMetadata,
+	load: F,
+	tiles: HashMap<(i16, i16), Option<Arc<Tile>>>,
+}
+
+impl<F, E> Sampler<F>
+where
+	F: FnMut(i16, i16) -> Result<Option<Arc<Tile>>, E>,
+{
+	fn new(metadata: TileMetadata, load: F) -> Self {
+		Self {
+			metadata,
+			load,
+			tiles: HashMap::new(),
+		}
+	}
+
+	fn sample(&mut self, lat: f64, lon: f64) -> Result<Elevation, E> {
+		let rows_per_degree = self.metadata.resolution as f64 * self.metadata.tiles_per_degree as f64;
+		let rows = self.metadata.lat_tiles() as i64 * self.metadata.resolution as i64;
+
+		// Global row, with the origin at the north pole. Rows can have different widths, so each is sampled separately.
+		let y = ((90.0 - lat) * rows_per_degree).clamp(0.0, (rows - 1) as f64);
+		let y0 = y.floor() as i64;
+		let fy = (y - y0 as f64) as f32;
+
+		let top = self.sample_row(y0, lon)?;
+		let bottom = self.sample_row((y0 + 1).min(rows - 1), lon)?;
+
+		Ok(Elevation {
+			height: lerp(top.0, bottom.0, fy),
+			water: lerp(top.1, bottom.1, fy) > 0.5,
+		})
+	}
+
+	/// Returns the linearly interpolated height in meters and water mask along a global row.
+	fn sample_row(&mut self, y: i64, lon: f64) -> Result<(f32, f32), E> {
+		let lat = self.tile_lat(y);
+		let width = self.metadata.tile_width(lat) as i64;
+		let cols = self.metadata.lon_tiles() as i64 * width;
+
+		let x = (lon + 180.0).rem_euclid(360.0) * self.metadata.tiles_per_degree as f64 * width as f64;
+		let x0 = x.floor() as i64;
+		let fx = (x - x0 as f64) as f32;
+
+		let left = self.load_pixel(x0.rem_euclid(cols), y, width)?;
+		let right = self.load_pixel((x0 + 1).rem_euclid(cols), y, width)?;
+
+		Ok((lerp(left.0, right.0, fx), lerp(left.1, right.1, fx)))
+	}
+
+	/// Returns the height in meters and the water mask of a global pixel.
+	fn load_pixel(&mut self, x: i64, y: i64, width: i64) -> Result<(f32, f32), E> {
+		let res = self.metadata.resolution as i64;
+		let lat = self.tile_lat(y);
+		let lon = (x / width - 180 * self.metadata.tiles_per_degree as i64) as i16;
+
+		if !self.tiles.contains_key(&(lat, lon)) {
+			let tile = (self.load)(lat, lon)?;
+			self.tiles.insert((lat, lon), tile);
+		}
+
+		Ok(match &self.tiles[&(lat, lon)] {
+			Some(tile) => {
+				let index = ((y % res) * width + x % width) as usize;
+				(tile.heights[index] as f32 - 500.0, tile.water[index] as f32)
+			},
+			None => (0.0, 1.0),
+		})
+	}
+
+	fn tile_lat(&self, y: i64) -> i16 {
+		(90 * self.metadata.tiles_per_degree as i64 - 1 - y / self.metadata.resolution as i64) as i16
+	}
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 { a + (b - a) * t }
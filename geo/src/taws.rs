@@ -0,0 +1,419 @@
+use std::{
+	error::Error,
+	fmt::{Debug, Display},
+};
+
+use crate::{great_circle_destination, CachedDataset, Dataset, Elevation};
+
+const FEET_PER_METER: f32 = 3.28084;
+const METERS_PER_SECOND_PER_KNOT: f64 = 0.514444;
+
+/// The state of the aircraft that the flight path is predicted from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AircraftState {
+	/// Latitude, in degrees.
+	pub lat: f64,
+	/// Longitude, in degrees.
+	pub lon: f64,
+	/// Altitude above sea level, in feet.
+	pub altitude: f32,
+	/// Track over the ground, in degrees clockwise from north.
+	pub track: f32,
+	/// Ground speed, in knots.
+	pub ground_speed: f32,
+	/// Vertical speed, in feet per minute.
+	pub vertical_speed: f32,
+}
+
+/// The shape of the envelope around the predicted flight path that terrain is probed in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LookAhead {
+	/// How far ahead terrain raises a caution, in seconds.
+	pub caution_time: f32,
+	/// How far ahead terrain raises a warning, in seconds.
+	pub warning_time: f32,
+	/// How far below the flight path terrain must stay, in feet. The default matches the bottom of the yellow band of
+	/// the TAWS map.
+	pub clearance: f32,
+	/// The half width of the envelope at the aircraft, in meters.
+	pub half_width: f32,
+	/// The angle the envelope widens by on each side, in degrees.
+	pub spread: f32,
+	/// The time between rows of probes along the path, in seconds.
+	pub time_step: f32,
+	/// The largest distance between probes across the path, in meters.
+	pub probe_spacing: f32,
+}
+
+impl LookAhead {
+	/// The most probes a look-ahead can take, so that an envelope that is very long or finely spaced for the ground
+	/// speed is rejected instead of taking unbounded time and memory.
+	pub const MAX_PROBES: usize = 1 << 16;
+
+	/// Check that every field is a number in range.
+	pub fn validate(&self) -> Result<(), LookAheadError> {
+		let non_negative = |x: f32| x >= 0.0 && x.is_finite();
+		if !non_negative(self.caution_time) || !non_negative(self.warning_time) {
+			return Err(LookAheadError::InvalidTime);
+		}
+		if !(self.time_step > 0.0 && self.time_step.is_finite()) {
+			return Err(LookAheadError::InvalidTimeStep);
+		}
+		if !self.clearance.is_finite()
+			|| !non_negative(self.half_width)
+			|| !(0.0..90.0).contains(&self.spread)
+			|| !(self.probe_spacing > 0.0 && self.probe_spacing.is_finite())
+		{
+			return Err(LookAheadError::InvalidEnvelope);
+		}
+
+		Ok(())
+	}
+}
+
+impl Default for LookAhead {
+	fn default() -> Self {
+		Self {
+			caution_time: 60.0,
+			warning_time: 30.0,
+			clearance: 500.0,
+			half_width: 460.0,
+			spread: 3.0,
+			time_step: 1.0,
+			probe_spacing: 150.0,
+		}
+	}
+}
+
+/// Why a [`LookAhead`] can't be probed.
+pub enum LookAheadError {
+	/// The caution or warning time is negative or not a number.
+	InvalidTime,
+	/// The time step is not a positive number.
+	InvalidTimeStep,
+	/// The clearance is not a number, the half width is negative or not a number, the spread is outside 0 to 90
+	/// degrees, or the probe spacing is not a positive number.
+	InvalidEnvelope,
+	/// The envelope would take more than [`LookAhead::MAX_PROBES`] probes at the ground speed of the aircraft.
+	TooManyProbes,
+}
+
+impl Display for LookAheadError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Self::InvalidTime => write!(f, "Look-ahead times must be non-negative"),
+			Self::InvalidTimeStep => write!(f, "Look-ahead time step must be positive"),
+			Self::InvalidEnvelope => write!(f, "Invalid look-ahead envelope"),
+			Self::TooManyProbes => write!(f, "Look-ahead needs more than {} probes", LookAhead::MAX_PROBES),
+		}
+	}
+}
+
+impl Debug for LookAheadError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { Display::fmt(self, f) }
+}
+
+impl Error for LookAheadError {}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum AlertLevel {
+	Caution,
+	Warning,
+}
+
+/// A probe where the terrain is within the clearance of the predicted flight path.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TerrainConflict {
+	/// Latitude, in degrees.
+	pub lat: f64,
+	/// Longitude, in degrees.
+	pub lon: f64,
+	/// Height of the terrain above sea level, in feet.
+	pub elevation: f32,
+	/// Seconds until the aircraft reaches the probe.
+	pub time_to_impact: f32,
+	pub level: AlertLevel,
+}
+
+/// The result of probing the terrain ahead of the aircraft.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TerrainAlert {
+	/// The most severe level of any conflict, or `None` if the path is clear.
+	pub level: Option<AlertLevel>,
+	/// Seconds until the nearest conflict.
+	pub time_to_impact: Option<f32>,
+	/// Every conflicting probe, in ascending order of time.
+	pub conflicts: Vec<TerrainConflict>,
+	/// The number of probes where the terrain could not be decoded. They are treated as clear, so the caller should
+	/// warn that terrain data is unavailable when this is not zero.
+	pub unknown_probes: usize,
+}
+
+impl Dataset {
+	/// Probe the terrain along the predicted flight path of `aircraft`.
+	///
+	/// The path follows the current track in a straight line, and descends at the current vertical speed. Climbs are
+	/// not credited, since the aircraft may level off. Alerts are not inhibited on the ground, which is left to the
+	/// caller.
+	///
+	/// Tiles that can't be decoded don't fail the evaluation, and are counted in
+	/// [`unknown_probes`](TerrainAlert::unknown_probes) instead.
+	pub fn terrain_alert(
+		&self, aircraft: &AircraftState, look_ahead: &LookAhead,
+	) -> Result<TerrainAlert, LookAheadError> {
+		tracy::zone!("Terrain Alert");
+
+		probe(aircraft, look_ahead, |points| self.elevations_or_unknown(points))
+	}
+}
+
+impl CachedDataset {
+	/// Same as [`Dataset::terrain_alert`], but goes through the cache.
+	pub fn terrain_alert(
+		&self, aircraft: &AircraftState, look_ahead: &LookAhead,
+	) -> Result<TerrainAlert, LookAheadError> {
+		tracy::zone!("Terrain Alert");
+
+		probe(aircraft, look_ahead, |points| self.elevations_or_unknown(points))
+	}
+}
+
+fn probe(
+	aircraft: &AircraftState, look_ahead: &LookAhead, elevations: impl FnOnce(&[(f64, f64)]) -> Vec<Option<Elevation>>,
+) -> Result<TerrainAlert, LookAheadError> {
+	look_ahead.validate()?;
+
+	let speed = aircraft.ground_speed.max(0.0) as f64 * METERS_PER_SECOND_PER_KNOT;
+	let descent = aircraft.vertical_speed.min(0.0) / 60.0;
+	let track = aircraft.track.to_radians() as f64;
+	let spread = (look_ahead.spread as f64).to_radians().tan();
+
+	// The envelope is widest at its end.
+	let rows = (look_ahead.caution_time as f64 / look_ahead.time_step as f64).ceil() + 1.0;
+	let widest = 2.0 * (look_ahead.half_width as f64 + speed * look_ahead.caution_time as f64 * spread);
+	let columns = (widest / look_ahead.probe_spacing as f64).ceil().max(1.0) + 1.0;
+	if rows * columns > LookAhead::MAX_PROBES as f64 {
+		return Err(LookAheadError::TooManyProbes);
+	}
+	let steps = rows as usize - 1;
+
+	let mut points = Vec::new();
+	let mut probes = Vec::new();
+	for step in 0..=steps {
+		let time = (step as f32 * look_ahead.time_step).min(look_ahead.caution_time);
+		let distance = speed * time as f64;
+		let center = great_circle_destination((aircraft.lat, aircraft.lon), track, distance);
+
+		let half_width = look_ahead.half_width as f64 + distance * spread;
+		let across = (2.0 * half_width / look_ahead.probe_spacing as f64).ceil().max(1.0) as usize;
+		for i in 0..=across {
+			let offset = -half_width + 2.0 * half_width * i as f64 / across as f64;
+			points.push(great_circle_destination(
+				center,
+				track + std::f64::consts::FRAC_PI_2,
+				offset,
+			));
+			// The time the aircraft reaches the probe, and its altitude there.
+			probes.push((time, aircraft.altitude + descent * time));
+		}
+	}
+
+	let mut alert = TerrainAlert::default();
+	for ((&(lat, lon), (time, altitude)), elevation) in points.iter().zip(probes).zip(elevations(&points)) {
+		let elevation = match elevation {
+			Some(x) => x.height * FEET_PER_METER,
+			None => {
+				alert.unknown_probes += 1;
+				continue;
+			},
+		};
+		if elevation + look_ahead.clearance <= altitude {
+			continue;
+		}
+
+		let level = if time <= look_ahead.warning_time {
+			AlertLevel::Warning
+		} else {
+			AlertLevel::Caution
+		};
+		alert.level = alert.level.max(Some(level));
+		alert.time_to_impact.get_or_insert(time);
+		alert.conflicts.push(TerrainConflict {
+			lat,
+			lon,
+			elevation,
+			time_to_impact: time,
+			level,
+		});
+	}
+
+	Ok(alert)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::EARTH_RADIUS;
+
+	/// Flying east along the equator, so that the distance along the path is the longitude.
+	const AIRCRAFT: AircraftState = AircraftState {
+		lat: 0.0,
+		lon: 0.0,
+		altitude: 3000.0,
+		track: 90.0,
+		ground_speed: 240.0,
+		vertical_speed: 0.0,
+	};
+
+	fn speed() -> f64 { AIRCRAFT.ground_speed as f64 * METERS_PER_SECOND_PER_KNOT }
+
+	/// Terrain of `height` feet everywhere.
+	fn flat(height: f32) -> impl FnOnce(&[(f64, f64)]) -> Vec<Option<Elevation>> {
+		move |points| {
+			points
+				.iter()
+				.map(|_| {
+					Some(Elevation {
+						height: height / FEET_PER_METER,
+						water: false,
+					})
+				})
+				.collect()
+		}
+	}
+
+	/// Terrain of `height` feet from `time` seconds ahead of the aircraft, and at sea level before it.
+	fn ridge(time: f64, height: f32) -> impl FnOnce(&[(f64, f64)]) -> Vec<Option<Elevation>> {
+		move |points| {
+			points
+				.iter()
+				.map(|&(_, lon)| {
+					let ahead = lon.to_radians() * EARTH_RADIUS >= speed() * time;
+					Some(Elevation {
+						height: if ahead { height / FEET_PER_METER } else { 0.0 },
+						water: false,
+					})
+				})
+				.collect()
+		}
+	}
+
+	#[test]
+	fn level_flight_into_ridge() {
+		let look_ahead = LookAhead::default();
+
+		let alert = probe(&AIRCRAFT, &look_ahead, ridge(44.5, 4000.0)).unwrap();
+		assert_eq!(alert.level, Some(AlertLevel::Caution));
+		assert_eq!(alert.time_to_impact, Some(45.0));
+
+		let alert = probe(&AIRCRAFT, &look_ahead, ridge(20.5, 4000.0)).unwrap();
+		assert_eq!(alert.level, Some(AlertLevel::Warning));
+		assert_eq!(alert.time_to_impact, Some(21.0));
+
+		// Beyond the look-ahead.
+		let alert = probe(&AIRCRAFT, &look_ahead, ridge(60.5, 4000.0)).unwrap();
+		assert_eq!(alert.level, None);
+		assert!(alert.conflicts.is_empty());
+	}
+
+	#[test]
+	fn descent() {
+		let look_ahead = LookAhead::default();
+
+		// Level, the aircraft clears the terrain by 2000 feet.
+		let alert = probe(&AIRCRAFT, &look_ahead, flat(1000.0)).unwrap();
+		assert_eq!(alert.level, None);
+
+		// Descending at 40 feet per second, it is within the clearance after 37.5 seconds.
+		let descending = AircraftState {
+			vertical_speed: -2400.0,
+			..AIRCRAFT
+		};
+		let alert = probe(&descending, &look_ahead, flat(1000.0)).unwrap();
+		assert_eq!(alert.level, Some(AlertLevel::Caution));
+		assert_eq!(alert.time_to_impact, Some(38.0));
+
+		// Climbs are not credited.
+		let climbing = AircraftState {
+			vertical_speed: 2400.0,
+			altitude: 1200.0,
+			..AIRCRAFT
+		};
+		let alert = probe(&climbing, &look_ahead, flat(1000.0)).unwrap();
+		assert_eq!(alert.level, Some(AlertLevel::Warning));
+		assert_eq!(alert.time_to_impact, Some(0.0));
+	}
+
+	#[test]
+	fn caution_and_warning_times() {
+		let look_ahead = LookAhead::default();
+		let alert = probe(&AIRCRAFT, &look_ahead, flat(10000.0)).unwrap();
+
+		assert_eq!(alert.level, Some(AlertLevel::Warning));
+		assert_eq!(alert.time_to_impact, Some(0.0));
+		assert!(alert
+			.conflicts
+			.windows(2)
+			.all(|x| x[0].time_to_impact <= x[1].time_to_impact));
+		for conflict in &alert.conflicts {
+			let expected = if conflict.time_to_impact <= look_ahead.warning_time {
+				AlertLevel::Warning
+			} else {
+				AlertLevel::Caution
+			};
+			assert_eq!(conflict.level, expected);
+		}
+		assert_eq!(alert.conflicts.last().unwrap().time_to_impact, look_ahead.caution_time);
+	}
+
+	#[test]
+	fn unknown_terrain() {
+		let alert = probe(&AIRCRAFT, &LookAhead::default(), |points| vec![None; points.len()]).unwrap();
+
+		assert_eq!(alert.level, None);
+		assert!(alert.unknown_probes > 0);
+	}
+
+	#[test]
+	fn invalid_look_ahead() {
+		for time_step in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+			let look_ahead = LookAhead {
+				time_step,
+				..Default::default()
+			};
+			assert!(matches!(
+				probe(&AIRCRAFT, &look_ahead, flat(0.0)),
+				Err(LookAheadError::InvalidTimeStep)
+			));
+		}
+
+		for caution_time in [-1.0, f32::NAN] {
+			let look_ahead = LookAhead {
+				caution_time,
+				..Default::default()
+			};
+			assert!(matches!(
+				probe(&AIRCRAFT, &look_ahead, flat(0.0)),
+				Err(LookAheadError::InvalidTime)
+			));
+		}
+
+		let look_ahead = LookAhead {
+			probe_spacing: 0.0,
+			..Default::default()
+		};
+		assert!(matches!(
+			probe(&AIRCRAFT, &look_ahead, flat(0.0)),
+			Err(LookAheadError::InvalidEnvelope)
+		));
+
+		let look_ahead = LookAhead {
+			time_step: 1e-6,
+			..Default::default()
+		};
+		assert!(matches!(
+			probe(&AIRCRAFT, &look_ahead, flat(0.0)),
+			Err(LookAheadError::TooManyProbes)
+		));
+	}
+}
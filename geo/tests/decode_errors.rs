@@ -0,0 +1,177 @@
+//! Damaged datasets must be reported through `LoadError` and `DecodeError`, never by panicking.
+
+use std::path::PathBuf;
+
+use geo::{map_lat_lon_to_index, Dataset, DecodeError, LoadError, TileEntry, TileLayers, FORMAT_VERSION};
+
+const RESOLUTION: u16 = 16;
+/// The header and the offset table of a dataset with one degree tiles.
+const HEADER_SIZE: u64 = 32 + 360 * 180 * 24;
+
+/// A tile at (0, 0) whose entry is `entry`, with `data` after the offset table.
+struct Corpus {
+	entry: TileEntry,
+	data: Vec<u8>,
+}
+
+impl Corpus {
+	/// A tile with the given layers, stored directly after the offset table.
+	fn new(layers: [&[u8]; 3]) -> Self {
+		Self {
+			entry: TileEntry {
+				offset: HEADER_SIZE,
+				layer_lengths: layers.map(|x| x.len() as u32),
+				checksum: crc32fast::hash(&layers.concat()),
+			},
+			data: layers.concat(),
+		}
+	}
+
+	fn bytes(&self) -> Vec<u8> {
+		let mut header = [0; 32];
+		header[0..5].copy_from_slice(b"sussy");
+		header[5..7].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+		header[7..9].copy_from_slice(&RESOLUTION.to_le_bytes());
+		header[9..11].copy_from_slice(&1u16.to_le_bytes());
+		header[11..13].copy_from_slice(&1u16.to_le_bytes());
+
+		let mut table = vec![0; (HEADER_SIZE - 32) as usize];
+		let index = map_lat_lon_to_index(0, 0, 1) * 24;
+		let entry = &mut table[index..index + 24];
+		entry[0..8].copy_from_slice(&self.entry.offset.to_le_bytes());
+		for (i, length) in self.entry.layer_lengths.iter().enumerate() {
+			entry[8 + i * 4..12 + i * 4].copy_from_slice(&length.to_le_bytes());
+		}
+		entry[20..24].copy_from_slice(&self.entry.checksum.to_le_bytes());
+
+		let mut hasher = crc32fast::Hasher::new();
+		hasher.update(&header[0..28]);
+		hasher.update(&table);
+		header[28..32].copy_from_slice(&hasher.finalize().to_le_bytes());
+
+		[&header[..], &table, &self.data].concat()
+	}
+
+	fn load(&self, name: &str) -> Result<Dataset, LoadError> { load_bytes(name, &self.bytes()) }
+}
+
+fn load_bytes(name: &str, bytes: &[u8]) -> Result<Dataset, LoadError> {
+	let path: PathBuf = std::env::temp_dir().join(format!("geo-{}-{}.geo", name, std::process::id()));
+	std::fs::write(&path, bytes).unwrap();
+	let dataset = Dataset::load(&path);
+	std::fs::remove_file(&path).unwrap();
+	dataset
+}
+
+/// A small deterministic generator, so that failures can be reproduced.
+struct XorShift(u64);
+
+impl XorShift {
+	fn next(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	fn bytes(&mut self, len: usize) -> Vec<u8> { (0..len).map(|_| self.next() as u8).collect() }
+}
+
+/// A RIFF header that claims a frame of `len` bytes, followed by garbage.
+fn fake_webp(len: usize) -> Vec<u8> {
+	let mut frame = b"RIFF".to_vec();
+	frame.extend_from_slice(&(len as u32 - 8).to_le_bytes());
+	frame.extend_from_slice(b"WEBPVP8L");
+	frame.resize(len, 0x5a);
+	frame
+}
+
+#[test]
+fn truncated_header() {
+	let bytes = Corpus::new([&[1; 8], &[2; 8], &[3; 8]]).bytes();
+
+	assert!(matches!(load_bytes("header", &bytes[..20]), Err(LoadError::InvalidFileSize)));
+	assert!(matches!(
+		load_bytes("table", &bytes[..HEADER_SIZE as usize / 2]),
+		Err(LoadError::InvalidFileSize)
+	));
+}
+
+#[test]
+fn truncated_frame() {
+	let mut corpus = Corpus::new([&[1; 8], &[2; 8], &[3; 8]]);
+	corpus.data.truncate(20);
+	let dataset = corpus.load("truncated").unwrap();
+
+	assert!(matches!(dataset.get_full_tile(0, 0), Some(Err(DecodeError::TruncatedFrame))));
+	assert!(matches!(dataset.raw_tile(0, 0), Some(Err(DecodeError::TruncatedFrame))));
+	assert!(dataset.verify_tile(0, 0).unwrap().is_err());
+}
+
+#[test]
+fn bad_offset() {
+	for offset in [1, HEADER_SIZE - 1, HEADER_SIZE + 24, u64::MAX] {
+		let mut corpus = Corpus::new([&[1; 8], &[2; 8], &[3; 8]]);
+		corpus.entry.offset = offset;
+		let dataset = corpus.load("offset").unwrap();
+
+		assert!(
+			matches!(dataset.get_full_tile(0, 0), Some(Err(DecodeError::BadOffset))),
+			"offset {}",
+			offset
+		);
+	}
+}
+
+#[test]
+fn bad_hcomp() {
+	let garbage = XorShift(1).bytes(64);
+	let dataset = Corpus::new([&garbage, &fake_webp(32), &fake_webp(32)])
+		.load("hcomp")
+		.unwrap();
+
+	assert!(matches!(
+		dataset.get_tile_layers(0, 0, TileLayers::HEIGHT),
+		Some(Err(DecodeError::Hcomp(_)))
+	));
+}
+
+#[test]
+fn bad_webp() {
+	let garbage = XorShift(2).bytes(64);
+	for water in [Vec::new(), garbage.clone(), fake_webp(32), fake_webp(64)] {
+		let dataset = Corpus::new([&garbage, &water, &water]).load("webp").unwrap();
+
+		assert!(matches!(
+			dataset.get_tile_layers(0, 0, TileLayers::WATER),
+			Some(Err(DecodeError::Webp))
+		));
+		assert!(matches!(
+			dataset.get_tile_layers(0, 0, TileLayers::HILLSHADE),
+			Some(Err(DecodeError::Webp))
+		));
+	}
+}
+
+#[test]
+fn random_corpus() {
+	let mut rng = XorShift(0x9e3779b97f4a7c15);
+	for _ in 0..256 {
+		let lengths = [0, 1, 2].map(|_| rng.next() as usize % 128);
+		let layers = lengths.map(|x| rng.bytes(x));
+		let mut corpus = Corpus::new([&layers[0], &layers[1], &layers[2]]);
+		match rng.next() % 4 {
+			0 => corpus.data.truncate(rng.next() as usize % (corpus.data.len() + 1)),
+			1 => corpus.entry.offset = HEADER_SIZE + rng.next() % 256,
+			2 => corpus.entry.layer_lengths[rng.next() as usize % 3] = rng.next() as u32 % 256,
+			_ => {},
+		}
+		let dataset = corpus.load("random").unwrap();
+
+		for layers in 1..8 {
+			// Garbage may happen to decode, but must not panic.
+			let _ = dataset.get_tile_layers(0, 0, TileLayers::from_bits_truncate(layers));
+		}
+		let _ = dataset.verify_tile(0, 0);
+	}
+}
@@ -1,5 +1,6 @@
 use std::{
 	error::Error,
+	fmt::Display,
 	io::Write,
 	path::Path,
 	sync::{
@@ -9,11 +10,28 @@ use std::{
 	time::Duration,
 };
 
-use geo::{map_index_to_lat_lon, Dataset, DatasetBuilder, TileMetadata};
+use clap::Args;
+use geo::{map_index_to_lat_lon, DatasetBuilder, LoadError, TileMetadata, TileOrder};
 use rayon::prelude::*;
 
+/// Options for commands that build a dataset with [`for_tile_in_output`].
+#[derive(Args)]
+pub struct OutputOptions {
+	/// Replace the output if it already exists and cannot be continued, such as when it was generated with different
+	/// settings.
+	#[clap(long = "overwrite")]
+	overwrite: bool,
+	/// Buffer up to this many MiB of finished tiles, and write them in Hilbert order so that neighbouring tiles end up
+	/// close together in the file. Buffered tiles are not journaled, so they are lost if the process is killed, and
+	/// have to be generated again. `geoc compact` reorders a finished dataset without that risk.
+	#[clap(long = "order-buffer", default_value_t = 0)]
+	order_buffer: usize,
+}
+
+/// Add every tile of `metadata` that is not in `output` yet with `exec`, continuing from a previous run if `output`
+/// already exists.
 pub fn for_tile_in_output(
-	output: &Path, metadata: TileMetadata,
+	output: &Path, metadata: TileMetadata, options: &OutputOptions,
 	exec: impl Fn(i16, i16, &DatasetBuilder) -> Result<(), Box<dyn Error>> + Sync,
 ) {
 	let was_quit = Arc::new(AtomicBool::new(false));
@@ -29,17 +47,54 @@ pub fn for_tile_in_output(
 		handler_used.store(true, Ordering::Release);
 	});
 
-	fn make_builder(path: &Path, metadata: TileMetadata) -> Result<DatasetBuilder, std::io::Error> {
-		if let Ok(x) = Dataset::load(path) {
-			if metadata == x.metadata() {
+	fn make_builder(
+		path: &Path, metadata: TileMetadata, options: &OutputOptions,
+	) -> Result<DatasetBuilder, Box<dyn Error>> {
+		// The file is only recovered and continued if it has the same metadata, and left untouched otherwise.
+		let builder = match path.exists().then(|| DatasetBuilder::open(path, metadata)) {
+			Some(Ok((builder, recovery))) => {
 				println!("Continuing from last execution");
-				return DatasetBuilder::from_dataset(&path, x);
-			}
-		}
-		DatasetBuilder::new(&path, metadata)
+				if recovery.table_restored {
+					println!("Restored the offset table from an interrupted flush");
+				}
+				if recovery.tiles != 0 {
+					println!("Recovered {} tiles from the journal", recovery.tiles);
+				}
+				if recovery.reclaimed_bytes != 0 {
+					println!("Reclaimed {} unreferenced bytes", recovery.reclaimed_bytes);
+				}
+				builder
+			},
+			Some(Err(e)) if !options.overwrite => {
+				let reason = match e {
+					LoadError::MetadataMismatch => "it was generated with different settings".to_string(),
+					e => format!("it could not be loaded ({})", e),
+				};
+				return Err(format!(
+					"{} already exists, but cannot be continued because {}. Pass --overwrite to replace it",
+					path.display(),
+					reason
+				)
+				.into());
+			},
+			existing => {
+				if existing.is_some() {
+					println!("Overwriting {}", path.display());
+				}
+				drop(existing);
+				DatasetBuilder::new(path, metadata)?.with_journal()?
+			},
+		};
+
+		// Tiles are processed in Hilbert order, so buffering lets neighbouring tiles end up close together in the file.
+		Ok(if options.order_buffer != 0 {
+			builder.with_order(TileOrder::Hilbert, options.order_buffer * 1024 * 1024)
+		} else {
+			builder
+		})
 	}
 
-	let builder = match make_builder(&output, metadata) {
+	let builder = match make_builder(output, metadata, options) {
 		Ok(x) => x,
 		Err(e) => {
 			eprintln!("{}", e);
@@ -48,7 +103,7 @@ pub fn for_tile_in_output(
 	};
 	let rbuilder = &builder;
 
-	let tiles = 360 * 180;
+	let tiles = metadata.tile_count();
 	let counter = AtomicUsize::new(1);
 	let had_error = AtomicBool::new(false);
 	let had_error = &had_error;
@@ -62,13 +117,15 @@ pub fn for_tile_in_output(
 		});
 
 		print!("\r{}/{}", counter.load(Ordering::Relaxed), tiles);
-		(0..tiles).into_par_iter().for_each(|index| {
+		let mut indices: Vec<_> = (0..tiles).collect();
+		TileOrder::Hilbert.sort(&metadata, &mut indices);
+		indices.into_par_iter().for_each(|index| {
 			tracy::zone!("Process tile");
 			if was_quit.load(Ordering::Acquire) {
 				return;
 			}
 
-			let (lat, lon) = map_index_to_lat_lon(index);
+			let (lat, lon) = map_index_to_lat_lon(index, metadata.tiles_per_degree);
 			if !rbuilder.tile_exists(lat, lon) {
 				match exec(lat, lon, &rbuilder) {
 					Ok(_) => {},
@@ -93,3 +150,20 @@ pub fn for_tile_in_output(
 			Err(e) => println!("Error saving output: {}", e),
 		});
 }
+
+pub struct Size(pub usize);
+
+impl Display for Size {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let size = self.0;
+		if size < 1000 {
+			write!(f, "{} B", size)
+		} else if size < 1000 * 1000 {
+			write!(f, "{:.2} KB", size as f64 / 1000.0)
+		} else if size < 1000 * 1000 * 1000 {
+			write!(f, "{:.2} MiB", size as f64 / 1000.0 / 1000.0)
+		} else {
+			write!(f, "{:.2} GiB", size as f64 / 1000.0 / 1000.0 / 1000.0)
+		}
+	}
+}
@@ -0,0 +1,117 @@
+use std::{
+	io::Write,
+	path::{Path, PathBuf},
+};
+
+use clap::Args;
+use geo::{map_index_to_lat_lon, Dataset, DatasetBuilder, TileOrder, FORMAT_VERSION};
+
+use crate::common::Size;
+
+#[derive(Args)]
+/// Rewrite a dataset without unreferenced data, with tiles in a spatial order.
+pub struct Compact {
+	input: PathBuf,
+	#[clap(short = 'o', long = "output")]
+	output: PathBuf,
+	/// The order of tiles in the output: `row-major`, `hilbert`, or `z-order`.
+	#[clap(short = 'l', long = "layout", default_value = "hilbert", parse(try_from_str = parse_order))]
+	order: TileOrder,
+}
+
+fn parse_order(order: &str) -> Result<TileOrder, String> {
+	match order {
+		"row-major" => Ok(TileOrder::RowMajor),
+		"hilbert" => Ok(TileOrder::Hilbert),
+		"z-order" => Ok(TileOrder::ZOrder),
+		_ => Err("expected `row-major`, `hilbert`, or `z-order`".into()),
+	}
+}
+
+pub fn compact(compact: Compact) {
+	let source = match Dataset::load(&compact.input) {
+		Ok(source) => source,
+		Err(err) => {
+			eprintln!("Error loading data source: {:?}", err);
+			return;
+		},
+	};
+
+	let metadata = source.metadata();
+	if metadata.version != FORMAT_VERSION {
+		eprintln!(
+			"Dataset is at version {}, upgrade it to version {} first",
+			metadata.version, FORMAT_VERSION
+		);
+		return;
+	}
+
+	let mut indices: Vec<_> = (0..metadata.tile_count())
+		.filter(|&index| {
+			let (lat, lon) = map_index_to_lat_lon(index, metadata.tiles_per_degree);
+			source.tile_exists(lat, lon)
+		})
+		.collect();
+	compact.order.sort(&metadata, &mut indices);
+
+	// The output is written next to its final path and renamed over it once complete, so the input is never
+	// overwritten while it is being read, even if both are the same file or hard links to it.
+	let mut temp = compact.output.as_os_str().to_owned();
+	temp.push(".tmp");
+	let temp = PathBuf::from(temp);
+	let before = size(&compact.input);
+
+	let builder = match DatasetBuilder::new(&temp, metadata) {
+		Ok(x) => x,
+		Err(e) => {
+			eprintln!("{}", e);
+			return;
+		},
+	};
+
+	// Tiles are copied without decoding, so a single thread is fast enough, and keeps them in order.
+	let mut skipped = 0;
+	for (i, &index) in indices.iter().enumerate() {
+		let (lat, lon) = map_index_to_lat_lon(index, metadata.tiles_per_degree);
+		let entry = source.tile_entry(lat, lon).unwrap();
+
+		let result = match source.verify_tile(lat, lon).unwrap() {
+			Ok(_) => source.raw_tile(lat, lon).unwrap().map_err(|e| e.to_string()),
+			Err(e) => Err(e.to_string()),
+		}
+		.and_then(|data| {
+			builder
+				.add_raw_tile(lat, lon, entry.layer_lengths, data)
+				.map_err(|e| e.to_string())
+		});
+		if let Err(e) = result {
+			println!("\nSkipping tile {}, {}: {}", lat, lon, e);
+			skipped += 1;
+		}
+
+		print!("\r{}/{}", i + 1, indices.len());
+		let _ = std::io::stdout().flush();
+	}
+	println!();
+
+	// Unmap the input before it can be replaced.
+	drop(source);
+	if let Err(e) = builder.finish().and_then(|_| std::fs::rename(&temp, &compact.output)) {
+		println!("Error saving output: {}", e);
+		let _ = std::fs::remove_file(&temp);
+		return;
+	}
+
+	let after = size(&compact.output);
+	println!(
+		"{} -> {}, saved {}",
+		Size(before),
+		Size(after),
+		Size(before.saturating_sub(after))
+	);
+	if skipped != 0 {
+		println!("{} corrupt tiles were skipped", skipped);
+	}
+}
+
+fn size(path: &Path) -> usize { std::fs::metadata(path).map(|x| x.len() as usize).unwrap_or(0) }
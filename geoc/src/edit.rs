@@ -1,4 +1,4 @@
-use std::{cell::RefCell, path::PathBuf};
+use std::{cell::RefCell, collections::HashMap, path::PathBuf};
 
 use clap::Args;
 use geo::{Dataset, TileMetadata, FORMAT_VERSION};
@@ -10,7 +10,7 @@ use resize::{
 use rgb::FromSlice;
 use thread_local::ThreadLocal;
 
-use crate::common::for_tile_in_output;
+use crate::common::{for_tile_in_output, OutputOptions};
 
 #[derive(Args)]
 /// Create a new dataset derived from another.
@@ -18,6 +18,8 @@ pub struct Edit {
 	input: PathBuf,
 	#[clap(short = 'o', long = "output")]
 	output: PathBuf,
+	#[clap(flatten)]
+	options: OutputOptions,
 	#[clap(short = 'r', long = "res", default_value_t = 1024)]
 	resolution: u16,
 	#[clap(short = 's', long = "hres", default_value_t = 50)]
@@ -38,55 +40,43 @@ pub fn edit(edit: Edit) {
 		version: FORMAT_VERSION,
 		resolution: edit.resolution,
 		height_resolution: edit.height_resolution,
+		tiles_per_degree: source_metadata.tiles_per_degree,
+		width_bands: source_metadata.width_bands,
 	};
+	if !metadata.has_valid_width_bands() {
+		eprintln!(
+			"Resolution {} is not compatible with the width bands of the source",
+			edit.resolution
+		);
+		return;
+	}
 
 	let needs_resize = metadata.resolution != source_metadata.resolution;
 
-	let u16_resize = ThreadLocal::new();
-	let u8_resize = ThreadLocal::new();
+	let resizers = ThreadLocal::new();
 
-	for_tile_in_output(&edit.output, metadata, |lat, lon, builder| {
-		if let Some((data, water, hillshade)) = source.get_full_tile(lat, lon).transpose()? {
+	for_tile_in_output(&edit.output, metadata, &edit.options, |lat, lon, builder| {
+		if let Some(tile) = source.get_full_tile(lat, lon).transpose()? {
 			let data = if needs_resize {
-				let mut u16_resize = u16_resize
-					.get_or(|| {
-						RefCell::new(
-							Resizer::new(
-								source_metadata.resolution as _,
-								source_metadata.resolution as _,
-								metadata.resolution as _,
-								metadata.resolution as _,
-								Gray16,
-								Type::Lanczos3,
-							)
-							.unwrap(),
-						)
-					})
-					.borrow_mut();
-				let mut u8_resize = u8_resize
-					.get_or(|| {
-						RefCell::new(
-							Resizer::new(
-								source_metadata.resolution as _,
-								source_metadata.resolution as _,
-								metadata.resolution as _,
-								metadata.resolution as _,
-								Gray8,
-								Type::Lanczos3,
-							)
-							.unwrap(),
-						)
-					})
-					.borrow_mut();
+				let width = metadata.tile_width(lat) as usize;
+				let height = metadata.resolution as usize;
 
-				let res = metadata.resolution as usize;
-				let mut data_out = vec![0; res * res];
-				let mut water_out = vec![0; res * res];
-				let mut hillshade_out = vec![0; res * res];
+				// Tiles in different width bands need different resizers.
+				let mut resizers = resizers.get_or(|| RefCell::new(HashMap::new())).borrow_mut();
+				let (u16_resize, u8_resize) = resizers.entry(tile.width).or_insert_with(|| {
+					(
+						Resizer::new(tile.width as _, tile.height as _, width, height, Gray16, Type::Lanczos3).unwrap(),
+						Resizer::new(tile.width as _, tile.height as _, width, height, Gray8, Type::Lanczos3).unwrap(),
+					)
+				});
 
-				let _ = u16_resize.resize(data.as_gray(), data_out.as_gray_mut());
-				let _ = u8_resize.resize(water.as_gray(), water_out.as_gray_mut());
-				let _ = u8_resize.resize(hillshade.as_gray(), hillshade_out.as_gray_mut());
+				let mut data_out = vec![0; width * height];
+				let mut water_out = vec![0; width * height];
+				let mut hillshade_out = vec![0; width * height];
+
+				let _ = u16_resize.resize(tile.heights.as_gray(), data_out.as_gray_mut());
+				let _ = u8_resize.resize(tile.water.as_gray(), water_out.as_gray_mut());
+				let _ = u8_resize.resize(tile.hillshade.as_gray(), hillshade_out.as_gray_mut());
 
 				if water_out.iter().all(|&x| x == 1) {
 					None
@@ -94,7 +84,7 @@ pub fn edit(edit: Edit) {
 					Some((data_out, water_out, hillshade_out))
 				}
 			} else {
-				Some((data, water, hillshade))
+				Some((tile.heights, tile.water, tile.hillshade))
 			};
 
 			if let Some(data) = data {
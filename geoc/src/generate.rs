@@ -1,10 +1,10 @@
 use std::path::PathBuf;
 
 use clap::Args;
-use geo::{TileMetadata, FORMAT_VERSION};
+use geo::{hillshade, hillshade_with_border, Dataset, Lighting, TileMetadata, WidthBand, FORMAT_VERSION};
 
 use crate::{
-	common::for_tile_in_output,
+	common::{for_tile_in_output, OutputOptions},
 	source::{LatLon, Raster},
 };
 
@@ -16,10 +16,43 @@ pub struct Generate {
 	water: PathBuf,
 	#[clap(short = 'o', long = "out")]
 	output: PathBuf,
+	#[clap(flatten)]
+	options: OutputOptions,
 	#[clap(short = 'r', long = "res", default_value_t = 1200)]
 	resolution: u16,
 	#[clap(short = 's', long = "hres", default_value_t = 1)]
 	height_resolution: u16,
+	#[clap(short = 't', long = "tpd", default_value_t = 1)]
+	tiles_per_degree: u16,
+	/// Narrow tiles at high latitudes, as `latitude:divisor` (e.g. `50:2`). Can be specified up to 4 times.
+	#[clap(short = 'b', long = "band", parse(try_from_str = parse_band))]
+	bands: Vec<WidthBand>,
+	/// The direction the hillshade is lit from, in degrees clockwise from north.
+	#[clap(long = "sun-azimuth", default_value_t = 315.0)]
+	sun_azimuth: f32,
+	/// The angle between the light of the hillshade and straight up, in degrees.
+	#[clap(long = "sun-zenith", default_value_t = 45.0)]
+	sun_zenith: f32,
+	/// Blend the hillshade from several directions around the azimuth.
+	#[clap(long = "multi-directional")]
+	multi_directional: bool,
+}
+
+fn parse_band(band: &str) -> Result<WidthBand, String> {
+	let (latitude, divisor) = band.split_once(':').ok_or("expected `latitude:divisor`")?;
+	let latitude = latitude.parse::<u8>().map_err(|e| e.to_string())?;
+	let divisor = divisor.parse::<u16>().map_err(|e| e.to_string())?;
+	if latitude > 90 {
+		return Err("latitude must be at most 90".into());
+	}
+	if !divisor.is_power_of_two() {
+		return Err("divisor must be a power of two".into());
+	}
+
+	Ok(WidthBand {
+		latitude,
+		shift: divisor.trailing_zeros() as u8,
+	})
 }
 
 pub fn generate(generate: Generate) {
@@ -37,158 +70,93 @@ pub fn generate(generate: Generate) {
 			return;
 		},
 	};
+	if generate.bands.len() > 4 {
+		eprintln!("At most 4 width bands are supported");
+		return;
+	}
+	let mut width_bands = [WidthBand::default(); 4];
+	width_bands[..generate.bands.len()].copy_from_slice(&generate.bands);
+
 	let metadata = TileMetadata {
 		version: FORMAT_VERSION,
 		resolution: generate.resolution,
 		height_resolution: generate.height_resolution,
+		tiles_per_degree: generate.tiles_per_degree,
+		width_bands,
+	};
+	if !(1..=Dataset::MAX_TILES_PER_DEGREE).contains(&metadata.tiles_per_degree) {
+		eprintln!(
+			"Tiles per degree must be between 1 and {}",
+			Dataset::MAX_TILES_PER_DEGREE
+		);
+		return;
+	}
+	if !metadata.has_valid_width_bands() {
+		eprintln!("Width bands must leave an even number of pixels in each tile");
+		return;
+	}
+	if !(0.0..=90.0).contains(&generate.sun_zenith) {
+		eprintln!("Sun zenith must be between 0 and 90 degrees");
+		return;
+	}
+
+	let lighting = Lighting {
+		azimuth: generate.sun_azimuth,
+		zenith: generate.sun_zenith,
+		multi_directional: generate.multi_directional,
 	};
 
-	for_tile_in_output(&generate.output, metadata, |lat, lon, builder| {
+	for_tile_in_output(&generate.output, metadata, &generate.options, |lat, lon, builder| {
+		let span = metadata.tile_span();
 		let bottom_left = LatLon {
-			lat: lat as f64,
-			lon: lon as f64,
+			lat: lat as f64 * span,
+			lon: lon as f64 * span,
 		};
 		let top_right = LatLon {
-			lat: (lat + 1) as f64,
-			lon: (lon + 1) as f64,
+			lat: (lat + 1) as f64 * span,
+			lon: (lon + 1) as f64 * span,
 		};
 
+		let width = metadata.tile_width(lat) as usize;
+		let height = metadata.resolution as usize;
+
 		source
-			.get_data_for_hillshade(bottom_left, top_right, metadata.resolution as _)
+			.get_data_for_hillshade(bottom_left, top_right, (width, height))
 			.and_then(|(data, has_extra): (Vec<i16>, _)| {
 				tracy::zone!("Load water");
 				water
-					.get_data(bottom_left, top_right, metadata.resolution as _)
+					.get_data(bottom_left, top_right, (width, height))
 					.map(|water: Vec<u8>| (data, has_extra, water))
 			})
 			.and_then(|(data, has_extra, water)| {
-				let res = metadata.resolution as usize;
-				assert!(res * res <= data.len());
+				assert!(width * height <= data.len());
 
-				let (data, hillshade) = if has_extra {
-					let ores = res;
-					let res = res + 2;
+				// Heights are offset by 500 so water is 0, which doesn't change the shading.
+				let data: Vec<u16> = data.into_iter().map(|h| (h + 500) as u16).collect();
 
+				let (data, hillshade) = if has_extra {
 					let hillshade = {
 						tracy::zone!("Generate hillshade");
-
-						let zenith = 45.0f32.to_radians();
-						let azimuth = 135.0f32.to_radians();
-
-						let mut out = vec![0; ores * ores];
-						for x in 1..res - 1 {
-							for y in 1..res - 1 {
-								let a = data[(y - 1) * res + x - 1] as f32;
-								let b = data[(y - 1) * res + x] as f32;
-								let c = data[(y - 1) * res + x + 1] as f32;
-								let d = data[y * res + x - 1] as f32;
-								let f = data[y * res + x + 1] as f32;
-								let g = data[(y + 1) * res + x - 1] as f32;
-								let h = data[(y + 1) * res + x] as f32;
-								let i = data[(y + 1) * res + x + 1] as f32;
-
-								let dzdx = ((c + 2.0 * f + i) - (a + 2.0 * d + g)) / 8.0;
-								let dzdy = ((g + 2.0 * h + i) - (a + 2.0 * b + c)) / 8.0;
-
-								let slope = (dzdx * dzdx + dzdy * dzdy).sqrt().atan();
-								let aspect = if dzdx != 0.0 {
-									let aspect = dzdy.atan2(-dzdx);
-									if aspect < 0.0 {
-										aspect + 2.0 * std::f32::consts::PI
-									} else {
-										aspect
-									}
-								} else {
-									if dzdy > 0.0 {
-										0.5 * std::f32::consts::PI
-									} else {
-										1.5 * std::f32::consts::PI
-									}
-								};
-
-								let hillshade = (zenith.cos() * slope.cos()
-									+ zenith.sin() * slope.sin() * (azimuth - aspect).cos())
-								.clamp(0.0, 1.0);
-
-								out[(y - 1) * ores + x - 1] = (hillshade * 255.0).round() as u8;
-							}
-						}
-
-						out
+						hillshade_with_border(&data, width, height, &lighting)
 					};
 
-					let mut out = vec![0; ores * ores];
-					for x in 1..res - 1 {
-						for y in 1..res - 1 {
-							out[(y - 1) * ores + x - 1] = data[y * res + x];
-						}
-					}
+					let stride = width + 2;
+					let data = (0..height)
+						.flat_map(|y| data[(y + 1) * stride + 1..(y + 1) * stride + 1 + width].iter().copied())
+						.collect();
 
-					(out, hillshade)
+					(data, hillshade)
 				} else {
 					let hillshade = {
 						tracy::zone!("Generate hillshade");
-
-						let zenith = 45.0f32.to_radians();
-						let azimuth = 135.0f32.to_radians();
-
-						let mut out = vec![0; res * res];
-						for x in 1..res - 1 {
-							for y in 1..res - 1 {
-								let a = data[(y - 1) * res + x - 1] as f32;
-								let b = data[(y - 1) * res + x] as f32;
-								let c = data[(y - 1) * res + x + 1] as f32;
-								let d = data[y * res + x - 1] as f32;
-								let f = data[y * res + x + 1] as f32;
-								let g = data[(y + 1) * res + x - 1] as f32;
-								let h = data[(y + 1) * res + x] as f32;
-								let i = data[(y + 1) * res + x + 1] as f32;
-
-								let dzdx = ((c + 2.0 * f + i) - (a + 2.0 * d + g)) / 8.0;
-								let dzdy = ((g + 2.0 * h + i) - (a + 2.0 * b + c)) / 8.0;
-
-								let slope = (dzdx * dzdx + dzdy * dzdy).sqrt().atan();
-								let aspect = if dzdx != 0.0 {
-									let aspect = dzdy.atan2(-dzdx);
-									if aspect < 0.0 {
-										aspect + 2.0 * std::f32::consts::PI
-									} else {
-										aspect
-									}
-								} else {
-									if dzdy > 0.0 {
-										0.5 * std::f32::consts::PI
-									} else {
-										1.5 * std::f32::consts::PI
-									}
-								};
-
-								let hillshade = (zenith.cos() * slope.cos()
-									+ zenith.sin() * slope.sin() * (azimuth - aspect).cos())
-								.clamp(0.0, 1.0);
-
-								out[y * res + x] = (hillshade * 255.0).round() as u8;
-							}
-						}
-
-						out
+						hillshade(&data, width, height, &lighting)
 					};
 
 					(data, hillshade)
 				};
 
-				let mut water_count = 0;
-				let data = data
-					.into_iter()
-					.zip(water.iter())
-					.map(|(h, &w)| {
-						let positive = (h + 500) as u16;
-						water_count += w as u32;
-						positive
-					})
-					.collect();
-
-				if water_count != metadata.resolution as u32 * metadata.resolution as u32 {
+				let water_count: u32 = water.iter().map(|&w| w as u32).sum();
+				if water_count != (width * height) as u32 {
 					Some(builder.add_tile(lat, lon, data, water, hillshade))
 				} else {
 					None
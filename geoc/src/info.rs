@@ -1,4 +1,4 @@
-use std::{fmt::Display, path::PathBuf};
+use std::path::PathBuf;
 
 use clap::Args;
 use geo::Dataset;
@@ -9,23 +9,6 @@ pub struct Info {
 	input: PathBuf,
 }
 
-struct Size(usize);
-
-impl Display for Size {
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		let size = self.0;
-		if size < 1000 {
-			write!(f, "{} B", size)
-		} else if size < 1000 * 1000 {
-			write!(f, "{:.2} KB", size as f64 / 1000.0)
-		} else if size < 1000 * 1000 * 1000 {
-			write!(f, "{:.2} MiB", size as f64 / 1000.0 / 1000.0)
-		} else {
-			write!(f, "{:.2} GiB", size as f64 / 1000.0 / 1000.0 / 1000.0)
-		}
-	}
-}
-
 pub fn info(info: Info) {
 	let dataset = match Dataset::load(&info.input) {
 		Ok(x) => x,
@@ -40,6 +23,14 @@ pub fn info(info: Info) {
 	println!("  Version: {}", metadata.version);
 	println!("  Resolution: {}", metadata.resolution);
 	println!("  Height resolution: {}", metadata.height_resolution);
+	println!("  Tiles per degree: {}", metadata.tiles_per_degree);
+	for band in metadata.width_bands.iter().filter(|band| band.shift != 0) {
+		println!(
+			"  Width band: {}° and above, width divided by {}",
+			band.latitude,
+			1 << band.shift
+		);
+	}
 
 	println!();
 
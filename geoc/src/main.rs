@@ -2,15 +2,18 @@ use clap::{Parser, Subcommand};
 
 #[cfg(feature = "generate")]
 use crate::generate::Generate;
-use crate::{edit::Edit, info::Info};
+use crate::{compact::Compact, edit::Edit, info::Info, upgrade::Upgrade, verify::Verify};
 
 mod common;
+mod compact;
 mod edit;
 #[cfg(feature = "generate")]
 mod generate;
 mod info;
 #[cfg(feature = "generate")]
 mod source;
+mod upgrade;
+mod verify;
 
 #[derive(Parser)]
 struct Options {
@@ -24,6 +27,9 @@ enum Command {
 	Generate(Generate),
 	Info(Info),
 	Edit(Edit),
+	Upgrade(Upgrade),
+	Verify(Verify),
+	Compact(Compact),
 }
 
 fn main() {
@@ -33,5 +39,8 @@ fn main() {
 		Command::Generate(generate) => generate::generate(generate),
 		Command::Info(info) => info::info(info),
 		Command::Edit(edit) => edit::edit(edit),
+		Command::Upgrade(upgrade) => upgrade::upgrade(upgrade),
+		Command::Verify(verify) => verify::verify(verify),
+		Command::Compact(compact) => compact::compact(compact),
 	}
 }
@@ -55,7 +55,9 @@ impl Raster {
 		})
 	}
 
-	pub fn get_data<T: GdalType + Copy>(&self, bottom_left: LatLon, top_right: LatLon, res: usize) -> Option<Vec<T>> {
+	pub fn get_data<T: GdalType + Copy>(
+		&self, bottom_left: LatLon, top_right: LatLon, res: (usize, usize),
+	) -> Option<Vec<T>> {
 		tracy::zone!("Get raster data");
 
 		let set = self
@@ -77,7 +79,7 @@ impl Raster {
 			.read_as(
 				(xl, yt),
 				((xr - xl) as usize, (yb - yt) as usize),
-				(res, res),
+				res,
 				Some(ResampleAlg::Lanczos),
 			)
 			.ok()
@@ -85,7 +87,7 @@ impl Raster {
 	}
 
 	pub fn get_data_for_hillshade<T: GdalType + Copy>(
-		&self, bottom_left: LatLon, top_right: LatLon, res: usize,
+		&self, bottom_left: LatLon, top_right: LatLon, res: (usize, usize),
 	) -> Option<(Vec<T>, bool)> {
 		tracy::zone!("Get raster data");
 
@@ -112,7 +114,7 @@ impl Raster {
 				.read_as(
 					(xl, yt),
 					((xr - xl) as usize, (yb - yt) as usize),
-					(res, res),
+					res,
 					Some(ResampleAlg::Lanczos),
 				)
 				.ok()
@@ -123,7 +125,7 @@ impl Raster {
 				.read_as(
 					(xl - 1, yt - 1),
 					((xr - xl) as usize + 2, (yb - yt) as usize + 2),
-					(res + 2, res + 2),
+					(res.0 + 2, res.1 + 2),
 					Some(ResampleAlg::Lanczos),
 				)
 				.ok()
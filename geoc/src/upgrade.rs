@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use geo::{Dataset, TileLayers, TileMetadata, FORMAT_VERSION};
+
+use crate::common::{for_tile_in_output, OutputOptions};
+
+#[derive(Args)]
+/// Rewrite a dataset from an older format version to the current one. Encoded layers are copied as they are, and only
+/// the hillshade of version 7 datasets is generated.
+pub struct Upgrade {
+	input: PathBuf,
+	#[clap(short = 'o', long = "output")]
+	output: PathBuf,
+	#[clap(flatten)]
+	options: OutputOptions,
+}
+
+pub fn upgrade(upgrade: Upgrade) {
+	let source = match Dataset::load(&upgrade.input) {
+		Ok(source) => source,
+		Err(err) => {
+			eprintln!("Error loading data source: {:?}", err);
+			return;
+		},
+	};
+
+	let source_metadata = source.metadata();
+	if source_metadata.version == FORMAT_VERSION {
+		println!("Dataset is already at version {}", FORMAT_VERSION);
+		return;
+	}
+	println!(
+		"Upgrading from version {} to {}",
+		source_metadata.version, FORMAT_VERSION
+	);
+
+	let metadata = TileMetadata {
+		version: FORMAT_VERSION,
+		..source_metadata
+	};
+
+	for_tile_in_output(&upgrade.output, metadata, &upgrade.options, |lat, lon, builder| {
+		let [height, water, hillshade] = match source.raw_layers(lat, lon).transpose()? {
+			Some(x) => x,
+			None => return Ok(()),
+		};
+
+		if source_metadata.version >= 8 {
+			builder.add_raw_layers(lat, lon, [height, water, hillshade])?;
+		} else {
+			// Version 7 doesn't store the hillshade, so it is generated from the heights.
+			let tile = source.get_tile_layers(lat, lon, TileLayers::HILLSHADE).unwrap()?;
+			builder.add_raw_tile_with_hillshade(lat, lon, height, water, &tile.hillshade)?;
+		}
+
+		Ok(())
+	});
+}
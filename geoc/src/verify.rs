@@ -0,0 +1,75 @@
+use std::{
+	io::Write,
+	path::PathBuf,
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+use clap::Args;
+use geo::{map_index_to_lat_lon, Dataset};
+use rayon::prelude::*;
+
+#[derive(Args)]
+/// Check every tile of the dataset for corruption, and exit with an error if any is corrupt.
+pub struct Verify {
+	input: PathBuf,
+}
+
+pub fn verify(verify: Verify) {
+	let dataset = match Dataset::load(&verify.input) {
+		Ok(x) => x,
+		Err(err) => {
+			eprintln!("Error loading dataset: {:?}", err);
+			std::process::exit(1);
+		},
+	};
+	let metadata = dataset.metadata();
+	if !metadata.has_tile_entries() {
+		println!(
+			"Version {} has no checksums, only checking that tiles can be decoded",
+			metadata.version
+		);
+	}
+
+	let tiles = metadata.tile_count();
+	let span = metadata.tile_span();
+	let counter = AtomicUsize::new(1);
+	let checked = AtomicUsize::new(0);
+	let corrupt = AtomicUsize::new(0);
+
+	(0..tiles).into_par_iter().for_each(|index| {
+		tracy::zone!("Verify tile");
+
+		let (lat, lon) = map_index_to_lat_lon(index, metadata.tiles_per_degree);
+		let error = match dataset.verify_tile(lat, lon) {
+			None => None,
+			Some(Err(e)) => Some(e.to_string()),
+			Some(Ok(_)) => match dataset.get_full_tile(lat, lon) {
+				Some(Err(e)) => Some(e.to_string()),
+				_ => None,
+			},
+		};
+
+		if dataset.tile_exists(lat, lon) {
+			checked.fetch_add(1, Ordering::Relaxed);
+		}
+		if let Some(error) = error {
+			corrupt.fetch_add(1, Ordering::Relaxed);
+			println!(
+				"\rCorrupt tile at {}°, {}°: {}",
+				lat as f64 * span,
+				lon as f64 * span,
+				error
+			);
+		}
+
+		print!("\r{}/{}", counter.fetch_add(1, Ordering::Relaxed), tiles);
+		let _ = std::io::stdout().flush();
+	});
+
+	let corrupt = corrupt.load(Ordering::Relaxed);
+	println!();
+	println!("{} tiles checked, {} corrupt", checked.load(Ordering::Relaxed), corrupt);
+	if corrupt != 0 {
+		std::process::exit(1);
+	}
+}
@@ -4,16 +4,20 @@ use std::{
 	num::{NonZeroU32, NonZeroUsize},
 	path::PathBuf,
 	sync::Mutex,
+	time::{Duration, Instant},
 };
 
 use dashmap::DashMap;
 use futures_lite::future::block_on;
 use png::{BitDepth, ColorType, Encoder};
-use render::{FrameOptions, LatLon, Renderer, RendererOptions};
+use render::{FrameOptions, LatLon, RenderMode, Renderer, RendererOptions, TileStorage};
 use rouille::{try_or_400::ErrJson, Request, Response};
 use tracy::wgpu::ProfileContext;
 use url::Url;
 
+/// How long a request waits for the tiles in view to load, before the tiles that are resident are returned.
+const LOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
 struct RenderData {
 	renderer: Renderer,
 	res: (u32, u32),
@@ -29,6 +33,7 @@ impl RenderData {
 			&RendererOptions {
 				data_path: path,
 				output_format: wgpu::TextureFormat::Rgba8UnormSrgb,
+				tile_storage: TileStorage::Atlas,
 			},
 		)
 		.unwrap();
@@ -116,6 +121,7 @@ fn main() {
 			let mut heading = 0.0;
 			let mut altitude = 0.0;
 			let mut range = 1.0;
+			let mut mode = RenderMode::Elevation;
 			for (key, val) in url.query_pairs() {
 				match key.as_ref() {
 					"id" => id = val.parse::<u32>()?,
@@ -132,6 +138,13 @@ fn main() {
 					"heading" => heading = val.parse()?,
 					"range" => range = val.parse()?,
 					"alt" => altitude = val.parse()?,
+					"mode" => {
+						mode = match val.as_ref() {
+							"elevation" => RenderMode::Elevation,
+							"taws" => RenderMode::Taws,
+							_ => return Err(From::from("unknown mode")),
+						}
+					},
 					_ => return Err(From::from("unknown query param")),
 				}
 			}
@@ -158,6 +171,9 @@ fn main() {
 					vertical_angle: range,
 					heading,
 					altitude,
+					mode,
+					tile_upload_budget: usize::MAX,
+					..Default::default()
 				};
 				renderer.renderer.render(&opts, &device, &queue, &view, &mut encoder);
 
@@ -165,8 +181,19 @@ fn main() {
 				let _ = queue.on_submitted_work_done();
 				device.poll(wgpu::Maintain::Wait);
 
+				// Keep rendering until every tile in view has been decoded and uploaded, or didn't fit in a full atlas.
+				// Give up after a while in case decoding stalls, and return what is resident.
+				let start = Instant::now();
 				let mut encoder = tracy::wgpu_command_encoder!(device, profiler, Default::default());
 				renderer.renderer.render(&opts, &device, &queue, &view, &mut encoder);
+				while renderer.renderer.is_loading() && start.elapsed() < LOAD_TIMEOUT {
+					queue.submit([encoder.finish()]);
+					device.poll(wgpu::Maintain::Wait);
+					std::thread::sleep(Duration::from_millis(1));
+
+					encoder = tracy::wgpu_command_encoder!(device, profiler, Default::default());
+					renderer.renderer.render(&opts, &device, &queue, &view, &mut encoder);
+				}
 
 				encoder.copy_texture_to_buffer(
 					wgpu::ImageCopyTexture {
@@ -1,5 +1,5 @@
 use egui::{Context, DragValue, Window};
-use render::{FrameOptions, Renderer, RendererOptions};
+use render::{ColorScheme, FrameOptions, MapMask, RenderMode, Renderer, RendererOptions, Shading, TileStorage};
 use tracy::wgpu::EncoderProfiler;
 use wgpu::{Device, Queue, TextureFormat, TextureView};
 
@@ -37,6 +37,7 @@ impl Ui {
 								&RendererOptions {
 									data_path: data,
 									output_format: format,
+									tile_storage: TileStorage::Atlas,
 								},
 							) {
 								Ok(x) => x,
@@ -83,6 +84,29 @@ impl Ui {
 				);
 			});
 
+			ui.horizontal(|ui| {
+				ui.label("Aircraft Position");
+				for x in self.options.center_offset.iter_mut() {
+					ui.add(DragValue::new(x).clamp_range(0.0..=1.0).speed(0.01));
+				}
+			});
+
+			ui.horizontal(|ui| {
+				ui.label("Mask");
+				let mut masked = self.options.mask.is_some();
+				ui.checkbox(&mut masked, "");
+				if masked {
+					let mask = self.options.mask.get_or_insert(MapMask {
+						radius: 0.7,
+						half_angle: 45.0,
+					});
+					ui.add(DragValue::new(&mut mask.radius).clamp_range(0.0..=2.0).speed(0.01));
+					ui.add(DragValue::new(&mut mask.half_angle).clamp_range(0.0..=180.0).speed(1.0));
+				} else {
+					self.options.mask = None;
+				}
+			});
+
 			ui.horizontal(|ui| {
 				ui.label("Aircraft Altitude");
 				ui.add(
@@ -91,6 +115,56 @@ impl Ui {
 						.speed(100.0),
 				);
 			});
+
+			ui.horizontal(|ui| {
+				ui.label("Mode");
+				ui.radio_value(&mut self.options.mode, RenderMode::Elevation, "Elevation");
+				ui.radio_value(&mut self.options.mode, RenderMode::Taws, "TAWS");
+			});
+
+			ui.horizontal(|ui| {
+				ui.label("Hillshade");
+				ui.radio_value(&mut self.options.shading, Shading::Stored, "Stored");
+				ui.radio_value(&mut self.options.shading, Shading::ScreenSpace, "Screen-space");
+			});
+
+			ui.horizontal(|ui| {
+				ui.label("Sun");
+				ui.add(
+					DragValue::new(&mut self.options.lighting.azimuth)
+						.clamp_range(0.0..=360.0)
+						.speed(1.0),
+				);
+				ui.add(
+					DragValue::new(&mut self.options.lighting.zenith)
+						.clamp_range(0.0..=90.0)
+						.speed(1.0),
+				);
+				ui.checkbox(&mut self.options.lighting.multi_directional, "Multi-directional");
+			});
+
+			ui.horizontal(|ui| {
+				ui.label("Colors");
+				if ui.button("...").clicked() {
+					if let Some(path) = rfd::FileDialog::new().pick_file() {
+						match ColorScheme::load(&path) {
+							Ok(x) => self.options.color_scheme = x,
+							Err(e) => log::error!("{}", e),
+						}
+					}
+				}
+				if ui.button("Default").clicked() {
+					self.options.color_scheme = ColorScheme::default();
+				}
+			});
+
+			if let Some(renderer) = self.renderer.as_ref() {
+				let stats = renderer.atlas_stats();
+				ui.label(format!(
+					"Atlas: {}x{}x{}, {} tiles in {}/{} pages",
+					stats.width, stats.height, stats.layers, stats.tiles, stats.used_pages, stats.pages
+				));
+			}
 		});
 
 		if let Some(renderer) = self.renderer.as_mut() {
@@ -0,0 +1,131 @@
+use std::num::NonZeroU32;
+
+use geo::{AlertLevel, TerrainConflict};
+use wgpu::{
+	Device,
+	Extent3d,
+	ImageDataLayout,
+	Queue,
+	Texture,
+	TextureDescriptor,
+	TextureDimension,
+	TextureFormat,
+	TextureUsages,
+	TextureView,
+	TextureViewDescriptor,
+};
+
+/// The conflicts of a terrain alert, rasterized into a grid of latitude and longitude cells that the output pass draws
+/// solid over the terrain. Each cell is 0 if it is clear, 1 for a caution, and 2 for a warning.
+pub struct AlertMap {
+	texture: Texture,
+	view: TextureView,
+	/// The conflicts in the texture.
+	conflicts: Vec<TerrainConflict>,
+	/// The south west corner of the grid, in degrees.
+	origin: (f64, f64),
+	/// The side of a cell, in degrees, or 0 if there are no conflicts.
+	cell_size: f64,
+}
+
+impl AlertMap {
+	/// The side of a cell, in degrees, unless the conflicts are too far apart to fit in the grid.
+	const CELL_SIZE: f64 = 1.0 / 240.0;
+	/// The number of cells along each side of the grid.
+	const SIZE: u32 = 128;
+
+	pub fn new(device: &Device) -> Self {
+		let texture = device.create_texture(&TextureDescriptor {
+			label: Some("Alert Map"),
+			size: Extent3d {
+				width: Self::SIZE,
+				height: Self::SIZE,
+				depth_or_array_layers: 1,
+			},
+			mip_level_count: 1,
+			sample_count: 1,
+			dimension: TextureDimension::D2,
+			format: TextureFormat::R8Uint,
+			usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+		});
+		let view = texture.create_view(&TextureViewDescriptor {
+			label: Some("Alert Map View"),
+			..Default::default()
+		});
+
+		Self {
+			texture,
+			view,
+			conflicts: Vec::new(),
+			origin: (0.0, 0.0),
+			cell_size: 0.0,
+		}
+	}
+
+	/// Rasterize `conflicts` into the grid, if they changed since the last call.
+	pub fn update(&mut self, queue: &Queue, conflicts: &[TerrainConflict]) {
+		if conflicts == self.conflicts {
+			return;
+		}
+
+		tracy::zone!("Alert Map Upload");
+
+		self.conflicts = conflicts.to_vec();
+		let size = Self::SIZE as usize;
+		let mut cells = vec![0; size * size];
+		match conflicts.first() {
+			Some(first) => {
+				// Longitudes are taken relative to the first conflict, so the grid can cross the antimeridian.
+				let relative = |lon: f64| (lon - first.lon + 180.0).rem_euclid(360.0) - 180.0;
+				let (mut south, mut north) = (first.lat, first.lat);
+				let (mut west, mut east) = (0.0f64, 0.0f64);
+				for conflict in conflicts {
+					south = south.min(conflict.lat);
+					north = north.max(conflict.lat);
+					west = west.min(relative(conflict.lon));
+					east = east.max(relative(conflict.lon));
+				}
+
+				self.origin = (south, first.lon + west);
+				self.cell_size = Self::CELL_SIZE.max((north - south).max(east - west) / (size - 1) as f64);
+				for conflict in conflicts {
+					let x = ((relative(conflict.lon) - west) / self.cell_size) as usize;
+					let y = ((conflict.lat - south) / self.cell_size) as usize;
+					let level = match conflict.level {
+						AlertLevel::Caution => 1,
+						AlertLevel::Warning => 2,
+					};
+					let cell = &mut cells[y.min(size - 1) * size + x.min(size - 1)];
+					*cell = (*cell).max(level);
+				}
+			},
+			None => self.cell_size = 0.0,
+		}
+
+		queue.write_texture(
+			self.texture.as_image_copy(),
+			&cells,
+			ImageDataLayout {
+				offset: 0,
+				bytes_per_row: Some(NonZeroU32::new(Self::SIZE).unwrap()),
+				rows_per_image: Some(NonZeroU32::new(Self::SIZE).unwrap()),
+			},
+			Extent3d {
+				width: Self::SIZE,
+				height: Self::SIZE,
+				depth_or_array_layers: 1,
+			},
+		);
+	}
+
+	pub fn view(&self) -> &TextureView { &self.view }
+
+	/// The south west corner of the grid, and the side of a cell, in radians.
+	pub fn uniforms(&self) -> [f32; 3] {
+		[
+			self.origin.0.to_radians() as f32,
+			self.origin.1.to_radians() as f32,
+			self.cell_size.to_radians() as f32,
+		]
+	}
+}
@@ -0,0 +1,379 @@
+use std::num::NonZeroU32;
+
+use wgpu::{
+	CommandEncoderDescriptor,
+	Device,
+	Extent3d,
+	ImageCopyTexture,
+	ImageDataLayout,
+	Origin3d,
+	Queue,
+	Texture,
+	TextureAspect,
+	TextureDescriptor,
+	TextureDimension,
+	TextureFormat,
+	TextureUsages,
+	TextureView,
+	TextureViewDescriptor,
+	TextureViewDimension,
+};
+
+/// Where a tile is in the atlas, as stored in the tile map. The layer is packed into the high 16 bits of `y`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TileOffset {
+	pub x: u32,
+	pub y: u32,
+}
+
+impl TileOffset {
+	fn new(x: u32, y: u32, layer: u32) -> Self {
+		Self {
+			x,
+			y: y | (layer << 16),
+		}
+	}
+
+	fn layer(self) -> u32 { self.y >> 16 }
+
+	fn y(self) -> u32 { self.y & 0xffff }
+}
+
+/// How tiles are stored on the GPU.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TileStorage {
+	/// Packed into a single 2D texture, which is limited by `max_texture_dimension_2d`.
+	Atlas,
+	/// In the layers of a 2D array texture, with each layer the size of the largest tile. Can hold many more tiles
+	/// within the device limits.
+	Array,
+}
+
+/// How much of the tile atlas is in use.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AtlasStats {
+	pub width: u32,
+	pub height: u32,
+	/// The number of tiles resident in the atlas.
+	pub tiles: usize,
+	/// The number of pages in the atlas.
+	pub pages: usize,
+	/// The number of pages that hold at least one tile.
+	pub used_pages: usize,
+	/// The pixels covered by resident tiles.
+	pub used_pixels: u64,
+	/// The number of layers in the atlas texture.
+	pub layers: u32,
+}
+
+/// A page of the atlas, split into slots of a single size.
+#[derive(Clone, Default)]
+struct Page {
+	/// The size of the slots, or `None` if the page is empty.
+	slot: Option<(u32, u32)>,
+	free: Vec<TileOffset>,
+	used: u32,
+}
+
+/// A texture that holds tiles of different sizes, and a companion texture with the same layout that holds their
+/// hillshades.
+///
+/// The atlas is split into square pages, and each page is split into slots the size of the first tile uploaded to it.
+/// A page is free to take another size once all of its tiles have been freed. With [`TileStorage::Array`], every
+/// layer is a single page.
+pub struct Atlas {
+	atlas: Texture,
+	view: TextureView,
+	hillshade: Texture,
+	hillshade_view: TextureView,
+	storage: TileStorage,
+	width: u32,
+	height: u32,
+	layers: u32,
+	page_size: u32,
+	/// The number of pages in a row and in a layer of the largest possible atlas, so growing the atlas doesn't move
+	/// pages.
+	columns: u32,
+	pages_per_layer: u32,
+	pages: Vec<Page>,
+}
+
+impl Atlas {
+	/// `page_size` must be at least as large as the largest tile.
+	pub fn new(device: &Device, storage: TileStorage, page_size: u32) -> Self {
+		let limits = device.limits();
+		let (width, height, layers, columns, max_layers) = match storage {
+			TileStorage::Atlas => {
+				let size = 4096.min(limits.max_texture_dimension_2d);
+				let columns = limits.max_texture_dimension_2d / page_size;
+				(size, size, 1, columns, 1)
+			},
+			TileStorage::Array => (
+				page_size,
+				page_size,
+				64.min(limits.max_texture_array_layers),
+				1,
+				limits.max_texture_array_layers,
+			),
+		};
+		let (atlas, view, hillshade, hillshade_view) = Self::make_atlas(device, width, height, layers);
+
+		Self {
+			atlas,
+			view,
+			hillshade,
+			hillshade_view,
+			storage,
+			width,
+			height,
+			layers,
+			page_size,
+			columns,
+			pages_per_layer: columns * columns,
+			pages: vec![Page::default(); (columns * columns * max_layers) as usize],
+		}
+	}
+
+	pub fn view(&self) -> &TextureView { &self.view }
+
+	pub fn hillshade_view(&self) -> &TextureView { &self.hillshade_view }
+
+	pub fn upload_tile(
+		&mut self, queue: &Queue, tile: &[u16], hillshade: &[u8], width: u32, height: u32,
+	) -> Option<TileOffset> {
+		tracy::zone!("Tile Upload");
+
+		let ret = self.allocate(width, height)?;
+
+		let origin = Origin3d {
+			x: ret.x,
+			y: ret.y(),
+			z: ret.layer(),
+		};
+		let size = Extent3d {
+			width,
+			height,
+			depth_or_array_layers: 1,
+		};
+
+		queue.write_texture(
+			ImageCopyTexture {
+				texture: &self.atlas,
+				mip_level: 0,
+				origin,
+				aspect: TextureAspect::All,
+			},
+			unsafe { std::slice::from_raw_parts(tile.as_ptr() as _, tile.len() * 2) },
+			ImageDataLayout {
+				offset: 0,
+				bytes_per_row: Some(NonZeroU32::new(2 * width).unwrap()),
+				rows_per_image: Some(NonZeroU32::new(height).unwrap()),
+			},
+			size,
+		);
+
+		if !hillshade.is_empty() {
+			queue.write_texture(
+				ImageCopyTexture {
+					texture: &self.hillshade,
+					mip_level: 0,
+					origin,
+					aspect: TextureAspect::All,
+				},
+				hillshade,
+				ImageDataLayout {
+					offset: 0,
+					bytes_per_row: Some(NonZeroU32::new(width).unwrap()),
+					rows_per_image: Some(NonZeroU32::new(height).unwrap()),
+				},
+				size,
+			);
+		}
+
+		Some(ret)
+	}
+
+	/// If a `width` * `height` tile can be uploaded without freeing any others.
+	pub fn has_space(&self, width: u32, height: u32) -> bool { self.find_page(width, height).is_some() }
+
+	/// Free the slot of a tile.
+	pub fn free(&mut self, tile: TileOffset) {
+		let index = self.page_index(tile);
+		let page = &mut self.pages[index];
+		page.used -= 1;
+		if page.used == 0 {
+			page.slot = None;
+			page.free.clear();
+		} else {
+			page.free.push(tile);
+		}
+	}
+
+	/// Double the size of the atlas, or the number of layers, keeping the tiles that are already resident in place.
+	/// Returns `false` if the atlas is as large as the device allows.
+	pub fn grow(&mut self, device: &Device, queue: &Queue) -> bool {
+		let limits = device.limits();
+		let (width, height, layers) = match self.storage {
+			TileStorage::Atlas => (
+				(self.width * 2).min(limits.max_texture_dimension_2d),
+				(self.height * 2).min(limits.max_texture_dimension_2d),
+				1,
+			),
+			TileStorage::Array => (
+				self.width,
+				self.height,
+				(self.layers * 2).min(limits.max_texture_array_layers),
+			),
+		};
+		if (width, height, layers) == (self.width, self.height, self.layers) {
+			log::error!("Atlas is too large to fit in device limits");
+			return false;
+		}
+
+		let (atlas, view, hillshade, hillshade_view) = Self::make_atlas(device, width, height, layers);
+
+		let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+			label: Some("Atlas Resize"),
+		});
+		let size = Extent3d {
+			width: self.width,
+			height: self.height,
+			depth_or_array_layers: self.layers,
+		};
+		encoder.copy_texture_to_texture(self.atlas.as_image_copy(), atlas.as_image_copy(), size);
+		encoder.copy_texture_to_texture(self.hillshade.as_image_copy(), hillshade.as_image_copy(), size);
+		queue.submit(Some(encoder.finish()));
+
+		self.atlas = atlas;
+		self.view = view;
+		self.hillshade = hillshade;
+		self.hillshade_view = hillshade_view;
+		self.width = width;
+		self.height = height;
+		self.layers = layers;
+
+		true
+	}
+
+	pub fn stats(&self) -> AtlasStats {
+		let mut stats = AtlasStats {
+			width: self.width,
+			height: self.height,
+			layers: self.layers,
+			..Default::default()
+		};
+		for index in self.page_indices() {
+			let page = &self.pages[index];
+			stats.pages += 1;
+			if let Some((width, height)) = page.slot {
+				stats.tiles += page.used as usize;
+				stats.used_pages += 1;
+				stats.used_pixels += page.used as u64 * width as u64 * height as u64;
+			}
+		}
+
+		stats
+	}
+
+	pub fn unloaded(&self) -> TileOffset { TileOffset { x: 0, y: self.height } }
+
+	pub fn not_found(&self) -> TileOffset { TileOffset { x: self.width, y: 0 } }
+
+	fn allocate(&mut self, width: u32, height: u32) -> Option<TileOffset> {
+		let index = self.find_page(width, height)?;
+		let page_size = self.page_size;
+		let origin = self.page_origin(index);
+
+		let page = &mut self.pages[index];
+		if page.slot.is_none() {
+			page.slot = Some((width, height));
+			// Reversed, so slots are handed out from the top left.
+			page.free = (0..page_size / height)
+				.rev()
+				.flat_map(|y| {
+					(0..page_size / width)
+						.rev()
+						.map(move |x| TileOffset::new(origin.x + x * width, origin.y() + y * height, origin.layer()))
+				})
+				.collect();
+		}
+
+		page.used += 1;
+		page.free.pop()
+	}
+
+	/// A page with a free slot for a `width` * `height` tile, or an empty page.
+	fn find_page(&self, width: u32, height: u32) -> Option<usize> {
+		let mut empty = None;
+		for index in self.page_indices() {
+			let page = &self.pages[index];
+			match page.slot {
+				Some(slot) if slot == (width, height) && !page.free.is_empty() => return Some(index),
+				None if empty.is_none() => empty = Some(index),
+				_ => {},
+			}
+		}
+
+		empty
+	}
+
+	/// The indices of the pages that are inside the atlas.
+	fn page_indices(&self) -> impl Iterator<Item = usize> {
+		let (columns, pages_per_layer) = (self.columns, self.pages_per_layer);
+		let (x, y) = (self.width / self.page_size, self.height / self.page_size);
+		(0..self.layers).flat_map(move |layer| {
+			(0..y).flat_map(move |y| (0..x).map(move |x| (layer * pages_per_layer + y * columns + x) as usize))
+		})
+	}
+
+	fn page_index(&self, tile: TileOffset) -> usize {
+		(tile.layer() * self.pages_per_layer + (tile.y() / self.page_size) * self.columns + tile.x / self.page_size)
+			as usize
+	}
+
+	fn page_origin(&self, index: usize) -> TileOffset {
+		let (layer, index) = (index as u32 / self.pages_per_layer, index as u32 % self.pages_per_layer);
+		TileOffset::new(
+			(index % self.columns) * self.page_size,
+			(index / self.columns) * self.page_size,
+			layer,
+		)
+	}
+
+	fn make_atlas(
+		device: &Device, width: u32, height: u32, layers: u32,
+	) -> (Texture, TextureView, Texture, TextureView) {
+		let mut descriptor = TextureDescriptor {
+			label: Some("Heightmap Atlas"),
+			size: Extent3d {
+				width,
+				height,
+				depth_or_array_layers: layers,
+			},
+			mip_level_count: 1,
+			sample_count: 1,
+			dimension: TextureDimension::D2,
+			format: TextureFormat::R16Uint,
+			usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST | TextureUsages::COPY_SRC,
+		};
+
+		let atlas = device.create_texture(&descriptor);
+		let view = atlas.create_view(&TextureViewDescriptor {
+			label: Some("Heightmap Atlas View"),
+			dimension: Some(TextureViewDimension::D2Array),
+			..Default::default()
+		});
+
+		descriptor.label = Some("Hillshade Atlas");
+		descriptor.format = TextureFormat::R8Uint;
+		let hillshade = device.create_texture(&descriptor);
+		let hillshade_view = hillshade.create_view(&TextureViewDescriptor {
+			label: Some("Hillshade Atlas View"),
+			dimension: Some(TextureViewDimension::D2Array),
+			..Default::default()
+		});
+
+		(atlas, view, hillshade, hillshade_view)
+	}
+}
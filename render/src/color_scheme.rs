@@ -0,0 +1,216 @@
+use std::{
+	error::Error,
+	fmt::{Debug, Display},
+	path::Path,
+	str::FromStr,
+};
+
+/// The color of terrain up to an elevation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ColorStop {
+	/// The elevation, in feet.
+	pub elevation: f32,
+	/// The gamma encoded color, with components from 0 to 1.
+	pub color: [f32; 3],
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ColorMode {
+	/// Terrain has the color of the lowest stop above it.
+	Banded,
+	/// Terrain blends between the colors of the stops below and above it.
+	Interpolated,
+}
+
+/// How terrain is colored by elevation.
+///
+/// Can be loaded from a file with one entry per line, and `#` starting comments:
+/// ```text
+/// mode banded
+/// water 0.01 0.09 0.31
+/// unknown 0.41 0.15 0.42
+/// stop 500 0.17 0.31 0.16
+/// stop 1000 0.22 0.36 0.19
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorScheme {
+	/// Stops in ascending order of elevation.
+	pub stops: Vec<ColorStop>,
+	pub water: [f32; 3],
+	/// The color of terrain above the last stop.
+	pub unknown: [f32; 3],
+	pub mode: ColorMode,
+}
+
+impl Default for ColorScheme {
+	fn default() -> Self {
+		let stops = [
+			(500.0, [0.17, 0.31, 0.16]),
+			(1000.0, [0.22, 0.36, 0.19]),
+			(2000.0, [0.33, 0.46, 0.21]),
+			(3000.0, [0.41, 0.51, 0.28]),
+			(4000.0, [0.49, 0.5, 0.3]),
+			(5000.0, [0.47, 0.52, 0.26]),
+			(6000.0, [0.46, 0.49, 0.29]),
+			(7000.0, [0.41, 0.43, 0.24]),
+			(8000.0, [0.45, 0.4, 0.22]),
+			(9000.0, [0.4, 0.35, 0.18]),
+			(10000.0, [0.33, 0.25, 0.12]),
+			(11000.0, [0.27, 0.21, 0.11]),
+			(12000.0, [0.31, 0.3, 0.25]),
+			(13000.0, [0.35, 0.38, 0.33]),
+			(15000.0, [0.43, 0.45, 0.43]),
+			(17000.0, [0.48, 0.48, 0.46]),
+			(19000.0, [0.51, 0.53, 0.52]),
+			(21000.0, [0.51, 0.55, 0.55]),
+			(33000.0, [0.56, 0.6, 0.6]),
+		];
+
+		Self {
+			stops: stops
+				.into_iter()
+				.map(|(elevation, color)| ColorStop { elevation, color })
+				.collect(),
+			water: [0.01, 0.09, 0.31],
+			unknown: [0.41, 0.15, 0.42],
+			mode: ColorMode::Banded,
+		}
+	}
+}
+
+impl ColorScheme {
+	/// The size of the uniform buffer the scheme is uploaded to.
+	pub(crate) const BUFFER_SIZE: usize = 48 + Self::MAX_STOPS * 16;
+	/// The most stops the renderer supports.
+	pub const MAX_STOPS: usize = 64;
+
+	pub fn load(path: &Path) -> Result<Self, ColorSchemeError> { std::fs::read_to_string(path)?.parse() }
+
+	/// The color of land at `elevation` feet, the same as the map colors it.
+	pub fn color_at(&self, elevation: f32) -> [f32; 3] {
+		for (i, stop) in self.stops.iter().enumerate() {
+			if elevation < stop.elevation {
+				if self.mode == ColorMode::Banded || i == 0 {
+					return stop.color;
+				}
+
+				let prev = self.stops[i - 1];
+				let t = (elevation - prev.elevation) / (stop.elevation - prev.elevation);
+				return std::array::from_fn(|c| prev.color[c] + (stop.color[c] - prev.color[c]) * t);
+			}
+		}
+
+		self.unknown
+	}
+
+	/// The uniform buffer layout:
+	/// * [0..12]: The water color.
+	/// * [16..28]: The unknown color.
+	/// * [32..36]: The number of stops.
+	/// * [36..40]: 1 if interpolated, 0 if banded.
+	/// * [48..]: The stops, as the color followed by the elevation.
+	pub(crate) fn to_buffer(&self) -> [u8; Self::BUFFER_SIZE] {
+		let mut data = [0; Self::BUFFER_SIZE];
+
+		let write_color = |data: &mut [u8], color: [f32; 3]| {
+			for (i, x) in color.into_iter().enumerate() {
+				data[i * 4..i * 4 + 4].copy_from_slice(&x.to_le_bytes());
+			}
+		};
+
+		write_color(&mut data[0..12], self.water);
+		write_color(&mut data[16..28], self.unknown);
+		let count = self.stops.len().min(Self::MAX_STOPS);
+		data[32..36].copy_from_slice(&(count as u32).to_le_bytes());
+		data[36..40].copy_from_slice(&((self.mode == ColorMode::Interpolated) as u32).to_le_bytes());
+		for (i, stop) in self.stops[..count].iter().enumerate() {
+			let offset = 48 + i * 16;
+			write_color(&mut data[offset..offset + 12], stop.color);
+			data[offset + 12..offset + 16].copy_from_slice(&stop.elevation.to_le_bytes());
+		}
+
+		data
+	}
+}
+
+impl FromStr for ColorScheme {
+	type Err = ColorSchemeError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let defaults = Self::default();
+		let mut scheme = Self {
+			stops: Vec::new(),
+			..defaults
+		};
+
+		for (i, line) in s.lines().enumerate() {
+			let error = |message: &str| ColorSchemeError::Parse {
+				line: i + 1,
+				message: message.into(),
+			};
+			let parse = |x: &str| x.parse::<f32>().map_err(|e| error(&e.to_string()));
+			let parse_color = |x: &[&str]| -> Result<[f32; 3], ColorSchemeError> {
+				match x {
+					[r, g, b] => Ok([parse(r)?, parse(g)?, parse(b)?]),
+					_ => Err(error("expected `r g b`")),
+				}
+			};
+
+			let line = line.split('#').next().unwrap();
+			let words: Vec<_> = line.split_whitespace().collect();
+			match words.as_slice() {
+				[] => {},
+				["mode", "banded"] => scheme.mode = ColorMode::Banded,
+				["mode", "interpolated"] => scheme.mode = ColorMode::Interpolated,
+				["mode", ..] => return Err(error("expected `mode banded` or `mode interpolated`")),
+				["water", color @ ..] => scheme.water = parse_color(color)?,
+				["unknown", color @ ..] => scheme.unknown = parse_color(color)?,
+				["stop", elevation, color @ ..] => {
+					let elevation = parse(elevation)?;
+					if scheme.stops.last().is_some_and(|x| x.elevation >= elevation) {
+						return Err(error("stops must be in ascending order of elevation"));
+					}
+					scheme.stops.push(ColorStop {
+						elevation,
+						color: parse_color(color)?,
+					});
+				},
+				_ => return Err(error("expected `mode`, `water`, `unknown`, or `stop`")),
+			}
+		}
+
+		if scheme.stops.is_empty() {
+			scheme.stops = defaults.stops;
+		} else if scheme.stops.len() > Self::MAX_STOPS {
+			return Err(ColorSchemeError::TooManyStops);
+		}
+
+		Ok(scheme)
+	}
+}
+
+pub enum ColorSchemeError {
+	Io(std::io::Error),
+	Parse { line: usize, message: String },
+	TooManyStops,
+}
+
+impl Display for ColorSchemeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Self::Io(err) => write!(f, "IO error: {}", err),
+			Self::Parse { line, message } => write!(f, "Line {}: {}", line, message),
+			Self::TooManyStops => write!(f, "At most {} stops are supported", ColorScheme::MAX_STOPS),
+		}
+	}
+}
+
+impl Debug for ColorSchemeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { <Self as Display>::fmt(self, f) }
+}
+
+impl Error for ColorSchemeError {}
+
+impl From<std::io::Error> for ColorSchemeError {
+	fn from(x: std::io::Error) -> Self { Self::Io(x) }
+}
@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
-use geo::LoadError;
+pub use geo::Lighting;
+use geo::{LoadError, TerrainConflict};
 use tracy::wgpu::EncoderProfiler;
 use wgpu::{
 	include_wgsl,
@@ -41,8 +42,20 @@ use wgpu::{
 	VertexState,
 };
 
-use crate::tile_cache::{TileCache, UploadStatus};
+use crate::{
+	alert_map::AlertMap,
+	tile_cache::{TileCache, UploadStatus},
+};
+pub use crate::{
+	atlas::{AtlasStats, TileStorage},
+	color_scheme::{ColorMode, ColorScheme, ColorSchemeError, ColorStop},
+	profile::{Profile, ProfileError, ProfileOptions},
+};
 
+mod alert_map;
+mod atlas;
+mod color_scheme;
+mod profile;
 pub mod range;
 mod tile_cache;
 
@@ -56,6 +69,42 @@ pub struct LatLon {
 pub struct RendererOptions {
 	pub data_path: PathBuf,
 	pub output_format: TextureFormat,
+	pub tile_storage: TileStorage,
+}
+
+/// Where the hillshade of the terrain comes from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Shading {
+	/// The hillshade stored in the datasets, which is computed from the full resolution heights.
+	Stored = 0,
+	/// A hillshade computed from the heights on screen.
+	ScreenSpace = 1,
+}
+
+/// What the terrain is colored by.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RenderMode {
+	/// The elevation of the terrain, from the color scheme.
+	Elevation = 0,
+	/// The elevation of the terrain relative to the aircraft, in the standard TAWS bands:
+	/// * More than 2000 feet above: 50% red dots.
+	/// * 1000 to 2000 feet above: 50% yellow dots.
+	/// * 500 feet below to 1000 feet above: 25% yellow dots.
+	/// * 500 to 1000 feet below: 50% green dots.
+	/// * 1000 to 2000 feet below: 16% green dots.
+	/// * More than 2000 feet below: black.
+	///
+	/// Water is treated as terrain at sea level.
+	Taws = 1,
+}
+
+/// The part of the screen around the aircraft that the map is drawn in. The rest is black.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MapMask {
+	/// The distance from the aircraft to the edge of the map, as a fraction of the height of the screen.
+	pub radius: f32,
+	/// How far the map extends either side of straight up, in degrees. 180 draws a full circle, and less draws an arc.
+	pub half_angle: f32,
 }
 
 pub struct FrameOptions {
@@ -63,14 +112,30 @@ pub struct FrameOptions {
 	pub width: u32,
 	/// The height of the output texture.
 	pub height: u32,
-	/// Position of the center of the map.
+	/// Position of the aircraft.
 	pub position: LatLon,
+	/// Where the aircraft is drawn, from (0, 0) at the top left of the screen to (1, 1) at the bottom right. The map
+	/// rotates around it with the heading. ARC mode puts it near the bottom, such as (0.5, 0.8).
+	pub center_offset: [f32; 2],
+	pub mask: Option<MapMask>,
 	/// Vertical angle of the screen, in radians.
 	pub vertical_angle: f32,
 	/// Heading of the aircraft, in degrees.
 	pub heading: f32,
-	/// Altitude of the aircraft, in meters.
+	/// Altitude of the aircraft, in feet, the same as the elevations of the color scheme and the TAWS bands.
 	pub altitude: f32,
+	pub mode: RenderMode,
+	/// The conflicts of the terrain alert ([`TerrainAlert::conflicts`](geo::TerrainAlert::conflicts)), drawn over
+	/// the terrain in either mode as solid cells, red for warnings and yellow for cautions.
+	pub terrain_conflicts: Vec<TerrainConflict>,
+	/// The maximum number of tiles to upload this frame. Tiles are decoded in the background, and drawn as
+	/// placeholders until they are uploaded.
+	pub tile_upload_budget: usize,
+	pub shading: Shading,
+	/// The light of screen-space shading. Its azimuth is relative to north, so the light follows the map as the
+	/// heading changes. To light from the same side of the screen at every heading, add the heading to the azimuth.
+	pub lighting: Lighting,
+	pub color_scheme: ColorScheme,
 }
 
 impl Default for FrameOptions {
@@ -79,9 +144,17 @@ impl Default for FrameOptions {
 			width: 100,
 			height: 100,
 			position: LatLon { lat: 0.0, lon: 0.0 },
+			center_offset: [0.5, 0.5],
+			mask: None,
 			vertical_angle: 0.297,
 			heading: 0.,
 			altitude: 10000.,
+			mode: RenderMode::Elevation,
+			terrain_conflicts: Vec::new(),
+			tile_upload_budget: 16,
+			shading: Shading::Stored,
+			lighting: Lighting::default(),
+			color_scheme: ColorScheme::default(),
 		}
 	}
 }
@@ -89,6 +162,10 @@ impl Default for FrameOptions {
 pub struct Renderer {
 	cache: TileCache,
 	cbuffer: Buffer,
+	colors: Buffer,
+	/// The color scheme in `colors`.
+	color_scheme: ColorScheme,
+	alert_map: AlertMap,
 	height_layout: BindGroupLayout,
 	height_pipeline: RenderPipeline,
 	height_group: BindGroup,
@@ -96,6 +173,7 @@ pub struct Renderer {
 	output_pipeline: RenderPipeline,
 	last_size: (u32, u32),
 	height_texture: Option<TextureView>,
+	hillshade_texture: Option<TextureView>,
 	output_group: Option<BindGroup>,
 }
 
@@ -105,7 +183,7 @@ impl Renderer {
 	pub fn new(device: &Device, options: &RendererOptions) -> Result<Self, LoadError> {
 		let sets = std::fs::read_to_string(options.data_path.join("_meta"))?;
 		let datasets = sets.lines().map(|line| options.data_path.join(line)).collect();
-		let cache = TileCache::new(device, datasets)?;
+		let cache = TileCache::new(device, datasets, options.tile_storage)?;
 
 		let cbuffer = device.create_buffer(&BufferDescriptor {
 			label: Some("Map Render Constant Buffer"),
@@ -114,6 +192,21 @@ impl Renderer {
 			mapped_at_creation: false,
 		});
 
+		let color_scheme = ColorScheme::default();
+		let colors = device.create_buffer(&BufferDescriptor {
+			label: Some("Color Scheme Buffer"),
+			size: ColorScheme::BUFFER_SIZE as _,
+			usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+			mapped_at_creation: true,
+		});
+		colors
+			.slice(..)
+			.get_mapped_range_mut()
+			.copy_from_slice(&color_scheme.to_buffer());
+		colors.unmap();
+
+		let alert_map = AlertMap::new(device);
+
 		let vertex = VertexState {
 			module: &device.create_shader_module(&include_wgsl!("shaders/fullscreen.wgsl")),
 			entry_point: "main",
@@ -138,7 +231,7 @@ impl Renderer {
 					visibility: ShaderStages::FRAGMENT,
 					ty: BindingType::Texture {
 						sample_type: TextureSampleType::Uint,
-						view_dimension: TextureViewDimension::D2,
+						view_dimension: TextureViewDimension::D2Array,
 						multisampled: false,
 					},
 					count: None,
@@ -158,7 +251,27 @@ impl Renderer {
 					visibility: ShaderStages::FRAGMENT,
 					ty: BindingType::Texture {
 						sample_type: TextureSampleType::Uint,
-						view_dimension: TextureViewDimension::D2,
+						view_dimension: TextureViewDimension::D2Array,
+						multisampled: false,
+					},
+					count: None,
+				},
+				BindGroupLayoutEntry {
+					binding: 4,
+					visibility: ShaderStages::FRAGMENT,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Storage { read_only: true },
+						has_dynamic_offset: false,
+						min_binding_size: None,
+					},
+					count: None,
+				},
+				BindGroupLayoutEntry {
+					binding: 5,
+					visibility: ShaderStages::FRAGMENT,
+					ty: BindingType::Texture {
+						sample_type: TextureSampleType::Uint,
+						view_dimension: TextureViewDimension::D2Array,
 						multisampled: false,
 					},
 					count: None,
@@ -180,7 +293,10 @@ impl Renderer {
 			fragment: Some(FragmentState {
 				module: &device.create_shader_module(&include_wgsl!("shaders/height.wgsl")),
 				entry_point: "main",
-				targets: &[ColorTargetState::from(TextureFormat::R16Uint)],
+				targets: &[
+					ColorTargetState::from(TextureFormat::R16Uint),
+					ColorTargetState::from(TextureFormat::R8Unorm),
+				],
 			}),
 			multiview: None,
 		});
@@ -210,6 +326,36 @@ impl Renderer {
 					},
 					count: None,
 				},
+				BindGroupLayoutEntry {
+					binding: 2,
+					visibility: ShaderStages::FRAGMENT,
+					ty: BindingType::Texture {
+						sample_type: TextureSampleType::Float { filterable: true },
+						view_dimension: TextureViewDimension::D2,
+						multisampled: false,
+					},
+					count: None,
+				},
+				BindGroupLayoutEntry {
+					binding: 3,
+					visibility: ShaderStages::FRAGMENT,
+					ty: BindingType::Buffer {
+						ty: BufferBindingType::Uniform,
+						has_dynamic_offset: false,
+						min_binding_size: None,
+					},
+					count: None,
+				},
+				BindGroupLayoutEntry {
+					binding: 4,
+					visibility: ShaderStages::FRAGMENT,
+					ty: BindingType::Texture {
+						sample_type: TextureSampleType::Uint,
+						view_dimension: TextureViewDimension::D2,
+						multisampled: false,
+					},
+					count: None,
+				},
 			],
 		});
 
@@ -235,6 +381,9 @@ impl Renderer {
 		Ok(Self {
 			cache,
 			cbuffer,
+			colors,
+			color_scheme,
+			alert_map,
 			height_pipeline,
 			height_group,
 			height_layout,
@@ -242,6 +391,7 @@ impl Renderer {
 			output_pipeline,
 			last_size: (0, 0),
 			height_texture: None,
+			hillshade_texture: None,
 			output_group: None,
 		})
 	}
@@ -252,10 +402,7 @@ impl Renderer {
 	) {
 		tracy::zone!("Map Render");
 
-		if let UploadStatus::Resized = self
-			.cache
-			.populate_tiles(device, queue, options.height, options.vertical_angle)
-		{
+		if let UploadStatus::Resized = self.cache.populate_tiles(device, queue, options.tile_upload_budget) {
 			self.height_group = Self::make_height_bind_group(device, &self.height_layout, &self.cbuffer, &self.cache);
 		}
 
@@ -263,19 +410,27 @@ impl Renderer {
 			tracy::zone!("Tile Status Clear");
 
 			encoder.clear_buffer(self.cache.tile_status(), 0, None);
-			queue.write_buffer(&self.cbuffer, 0, &Self::get_cbuffer_data(&self.cache, options));
+			self.alert_map.update(queue, &options.terrain_conflicts);
+			queue.write_buffer(&self.cbuffer, 0, &Self::get_cbuffer_data(options, &self.alert_map));
+			if options.color_scheme != self.color_scheme {
+				self.color_scheme = options.color_scheme.clone();
+				queue.write_buffer(&self.colors, 0, &self.color_scheme.to_buffer());
+			}
 		}
 
 		if self.last_size.0 != options.width || self.last_size.1 != options.height {
 			self.last_size = (options.width, options.height);
-			let (texture, group) = Self::make_height_texture(
+			let (texture, hillshade, group) = Self::make_height_texture(
 				device,
 				&self.output_layout,
 				&self.cbuffer,
+				&self.colors,
+				self.alert_map.view(),
 				options.width,
 				options.height,
 			);
 			self.height_texture = Some(texture);
+			self.hillshade_texture = Some(hillshade);
 			self.output_group = Some(group);
 		}
 
@@ -287,14 +442,24 @@ impl Renderer {
 					encoder,
 					RenderPassDescriptor {
 						label: Some("Heightmap Pass"),
-						color_attachments: &[RenderPassColorAttachment {
-							view: self.height_texture.as_ref().unwrap(),
-							resolve_target: None,
-							ops: Operations {
-								load: LoadOp::Clear(Color::BLACK),
-								store: true,
+						color_attachments: &[
+							RenderPassColorAttachment {
+								view: self.height_texture.as_ref().unwrap(),
+								resolve_target: None,
+								ops: Operations {
+									load: LoadOp::Clear(Color::BLACK),
+									store: true,
+								},
 							},
-						}],
+							RenderPassColorAttachment {
+								view: self.hillshade_texture.as_ref().unwrap(),
+								resolve_target: None,
+								ops: Operations {
+									load: LoadOp::Clear(Color::BLACK),
+									store: true,
+								},
+							},
+						],
 						depth_stencil_attachment: None,
 					}
 				);
@@ -326,6 +491,18 @@ impl Renderer {
 		}
 	}
 
+	/// If tiles in view are still being loaded, so the last frame was incomplete.
+	pub fn is_loading(&self) -> bool { self.cache.is_loading() }
+
+	/// How much of the tile atlas is in use.
+	pub fn atlas_stats(&self) -> AtlasStats { self.cache.atlas_stats() }
+
+	/// Draw a side view of the terrain along a path. Tiles are decoded on the calling thread, through the same cache
+	/// that feeds the map.
+	pub fn profile(&self, options: &ProfileOptions) -> Result<Profile, ProfileError> {
+		Profile::new(self.cache.datasets(), options)
+	}
+
 	fn make_height_bind_group(
 		device: &Device, layout: &BindGroupLayout, cbuffer: &Buffer, cache: &TileCache,
 	) -> BindGroup {
@@ -349,13 +526,22 @@ impl Renderer {
 					binding: 3,
 					resource: BindingResource::TextureView(&cache.atlas()),
 				},
+				BindGroupEntry {
+					binding: 4,
+					resource: cache.lods().as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 5,
+					resource: BindingResource::TextureView(cache.hillshade_atlas()),
+				},
 			],
 		})
 	}
 
 	fn make_height_texture(
-		device: &Device, layout: &BindGroupLayout, cbuffer: &Buffer, width: u32, height: u32,
-	) -> (TextureView, BindGroup) {
+		device: &Device, layout: &BindGroupLayout, cbuffer: &Buffer, colors: &Buffer, alert_map: &TextureView,
+		width: u32, height: u32,
+	) -> (TextureView, TextureView, BindGroup) {
 		let texture = device
 			.create_texture(&TextureDescriptor {
 				label: Some("Height Texture"),
@@ -375,6 +561,25 @@ impl Renderer {
 				..Default::default()
 			});
 
+		let hillshade = device
+			.create_texture(&TextureDescriptor {
+				label: Some("Hillshade Texture"),
+				size: Extent3d {
+					width,
+					height,
+					depth_or_array_layers: 1,
+				},
+				mip_level_count: 1,
+				sample_count: 1,
+				dimension: TextureDimension::D2,
+				format: TextureFormat::R8Unorm,
+				usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+			})
+			.create_view(&TextureViewDescriptor {
+				label: Some("Hillshade Texture View"),
+				..Default::default()
+			});
+
 		let group = device.create_bind_group(&BindGroupDescriptor {
 			label: Some("Output Bind Group"),
 			layout,
@@ -387,13 +592,25 @@ impl Renderer {
 					binding: 1,
 					resource: BindingResource::TextureView(&texture),
 				},
+				BindGroupEntry {
+					binding: 2,
+					resource: BindingResource::TextureView(&hillshade),
+				},
+				BindGroupEntry {
+					binding: 3,
+					resource: colors.as_entire_binding(),
+				},
+				BindGroupEntry {
+					binding: 4,
+					resource: BindingResource::TextureView(alert_map),
+				},
 			],
 		});
 
-		(texture, group)
+		(texture, hillshade, group)
 	}
 
-	fn get_cbuffer_data(cache: &TileCache, options: &FrameOptions) -> [u8; Self::CBUFFER_SIZE as _] {
+	fn get_cbuffer_data(options: &FrameOptions, alert_map: &AlertMap) -> [u8; Self::CBUFFER_SIZE as _] {
 		let mut data = [0; Self::CBUFFER_SIZE as _];
 
 		data[0..4].copy_from_slice(&options.position.lat.to_radians().to_le_bytes());
@@ -402,9 +619,24 @@ impl Renderer {
 		data[16..20].copy_from_slice(&options.vertical_angle.to_le_bytes());
 		data[20..24].copy_from_slice(&options.width.to_le_bytes());
 		data[24..28].copy_from_slice(&options.height.to_le_bytes());
-		data[28..32].copy_from_slice(&cache.tile_size().to_le_bytes());
-		data[32..36].copy_from_slice(&(360. - options.heading).to_radians().to_le_bytes());
-		data[36..40].copy_from_slice(&options.altitude.to_le_bytes());
+		data[28..32].copy_from_slice(&(360. - options.heading).to_radians().to_le_bytes());
+		// The shaders convert the terrain to feet, and compare it with the altitude in feet.
+		data[32..36].copy_from_slice(&options.altitude.to_le_bytes());
+		data[36..40].copy_from_slice(&(options.shading as u32).to_le_bytes());
+		data[40..44].copy_from_slice(&options.lighting.azimuth.to_radians().to_le_bytes());
+		data[44..48].copy_from_slice(&options.lighting.zenith.to_radians().to_le_bytes());
+		data[48..52].copy_from_slice(&(options.lighting.multi_directional as u32).to_le_bytes());
+		data[52..56].copy_from_slice(&(options.mode as u32).to_le_bytes());
+		data[56..60].copy_from_slice(&options.center_offset[0].to_le_bytes());
+		data[60..64].copy_from_slice(&options.center_offset[1].to_le_bytes());
+		if let Some(mask) = options.mask {
+			data[64..68].copy_from_slice(&mask.radius.to_le_bytes());
+			data[68..72].copy_from_slice(&mask.half_angle.to_radians().to_le_bytes());
+		}
+		let [alert_lat, alert_lon, alert_cell_size] = alert_map.uniforms();
+		data[72..76].copy_from_slice(&alert_lat.to_le_bytes());
+		data[76..80].copy_from_slice(&alert_lon.to_le_bytes());
+		data[80..84].copy_from_slice(&alert_cell_size.to_le_bytes());
 
 		data
 	}
@@ -0,0 +1,465 @@
+use std::{
+	error::Error,
+	f64::consts::FRAC_PI_2,
+	fmt::{Debug, Display},
+	sync::Arc,
+};
+
+use geo::{great_circle_destination, great_circle_distance, initial_bearing, CachedDataset, DecodeError, EARTH_RADIUS};
+
+use crate::{ColorScheme, LatLon, RenderMode};
+
+const METERS_PER_NAUTICAL_MILE: f64 = 1852.0;
+const FEET_PER_METER: f32 = 3.28084;
+
+const SKY: [f32; 3] = [0.0, 0.0, 0.0];
+const AIRCRAFT: [f32; 3] = [1.0, 1.0, 1.0];
+const TAWS_RED: [f32; 3] = [0.93, 0.12, 0.14];
+const TAWS_YELLOW: [f32; 3] = [0.99, 0.93, 0.09];
+const TAWS_GREEN: [f32; 3] = [0.11, 0.72, 0.16];
+/// Terrain well below the aircraft is black on the map, which wouldn't show against the sky.
+const TAWS_BELOW: [f32; 3] = [0.2, 0.2, 0.2];
+
+/// A side view of the terrain along a path, such as the track or the flight plan.
+pub struct ProfileOptions {
+	/// The path, starting at the aircraft, with at least one point. If it is shorter than `range`, it is extended
+	/// along its last leg, and a path of a single point runs north.
+	pub path: Vec<LatLon>,
+	/// The distance along the path covered by the profile, in nautical miles.
+	pub range: f32,
+	/// The width of the corridor around the path that each column takes the highest terrain from, in nautical miles.
+	pub corridor_width: f32,
+	/// The width of the image, and the number of columns.
+	pub width: u32,
+	/// The height of the image.
+	pub height: u32,
+	/// The elevation at the bottom of the image, in feet.
+	pub min_elevation: f32,
+	/// The elevation at the top of the image, in feet.
+	pub max_elevation: f32,
+	/// Altitude of the aircraft, in feet. Drawn as a line across the image.
+	pub altitude: f32,
+	/// Colors each pixel of terrain by its elevation, or by the TAWS band of its elevation relative to the aircraft.
+	/// The TAWS bands are drawn solid instead of as dots.
+	pub mode: RenderMode,
+	pub color_scheme: ColorScheme,
+}
+
+impl ProfileOptions {
+	/// The largest width or height of the image.
+	pub const MAX_IMAGE_SIZE: u32 = 8192;
+	/// The most elevations a profile can sample, so that a wide corridor over fine data is rejected instead of taking
+	/// unbounded time and memory.
+	pub const MAX_SAMPLES: usize = 1 << 20;
+
+	/// Check that every field is a number in range.
+	pub fn validate(&self) -> Result<(), ProfileError> {
+		if self.path.is_empty() || self.path.iter().any(|x| !x.lat.is_finite() || !x.lon.is_finite()) {
+			return Err(ProfileError::InvalidPath);
+		}
+		if !(self.range > 0.0 && self.range.is_finite())
+			|| !(self.corridor_width >= 0.0 && self.corridor_width.is_finite())
+		{
+			return Err(ProfileError::InvalidRange);
+		}
+		if !(1..=Self::MAX_IMAGE_SIZE).contains(&self.width) || !(1..=Self::MAX_IMAGE_SIZE).contains(&self.height) {
+			return Err(ProfileError::InvalidImageSize);
+		}
+		if !self.min_elevation.is_finite()
+			|| !self.max_elevation.is_finite()
+			|| self.max_elevation <= self.min_elevation
+			|| !self.altitude.is_finite()
+		{
+			return Err(ProfileError::InvalidElevations);
+		}
+
+		Ok(())
+	}
+}
+
+impl Default for ProfileOptions {
+	fn default() -> Self {
+		Self {
+			path: Vec::new(),
+			range: 40.0,
+			corridor_width: 1.0,
+			width: 512,
+			height: 128,
+			min_elevation: 0.0,
+			max_elevation: 20000.0,
+			altitude: 10000.0,
+			mode: RenderMode::Elevation,
+			color_scheme: ColorScheme::default(),
+		}
+	}
+}
+
+/// Why a profile couldn't be made.
+pub enum ProfileError {
+	/// There are no datasets to sample.
+	NoDatasets,
+	/// The path is empty, or has a coordinate that is not a number.
+	InvalidPath,
+	/// The range is not a positive number, or the corridor width is negative or not a number.
+	InvalidRange,
+	/// The width or height of the image is 0, or more than [`ProfileOptions::MAX_IMAGE_SIZE`].
+	InvalidImageSize,
+	/// The elevation range is empty, or it or the altitude is not a number.
+	InvalidElevations,
+	/// The profile would sample more than [`ProfileOptions::MAX_SAMPLES`] elevations.
+	TooManySamples,
+	Decode(DecodeError),
+}
+
+impl Display for ProfileError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Self::NoDatasets => write!(f, "No datasets to profile"),
+			Self::InvalidPath => write!(f, "Profile path must have at least one valid point"),
+			Self::InvalidRange => write!(f, "Invalid profile range or corridor width"),
+			Self::InvalidImageSize => write!(
+				f,
+				"Profile image must be 1 to {} pixels on each side",
+				ProfileOptions::MAX_IMAGE_SIZE
+			),
+			Self::InvalidElevations => write!(f, "Invalid profile elevation range or altitude"),
+			Self::TooManySamples => write!(f, "Profile needs more than {} samples", ProfileOptions::MAX_SAMPLES),
+			Self::Decode(err) => write!(f, "Decode error: {}", err),
+		}
+	}
+}
+
+impl Debug for ProfileError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { <Self as Display>::fmt(self, f) }
+}
+
+impl Error for ProfileError {}
+
+impl From<DecodeError> for ProfileError {
+	fn from(x: DecodeError) -> Self { Self::Decode(x) }
+}
+
+/// The terrain along a path.
+pub struct Profile {
+	/// The highest terrain in each column, in feet.
+	pub elevations: Vec<f32>,
+	/// If the highest terrain in each column is covered by water.
+	pub water: Vec<bool>,
+	/// The distance along the path covered by each column, in nautical miles.
+	pub column_length: f32,
+	pub width: u32,
+	pub height: u32,
+	/// The rendered profile, as gamma encoded RGBA8 rows from the top.
+	pub image: Vec<u8>,
+}
+
+impl Profile {
+	/// `datasets` must be ordered from the finest to the coarsest.
+	pub(crate) fn new(datasets: &[Arc<CachedDataset>], options: &ProfileOptions) -> Result<Self, ProfileError> {
+		tracy::zone!("Profile");
+
+		options.validate()?;
+		if datasets.is_empty() {
+			return Err(ProfileError::NoDatasets);
+		}
+
+		let columns = options.width as usize;
+		let column_length = options.range as f64 * METERS_PER_NAUTICAL_MILE / columns as f64;
+		let corridor = options.corridor_width as f64 * METERS_PER_NAUTICAL_MILE;
+
+		// The coarsest LOD with texels no larger than a column, the same as the map picks for a pixel.
+		let texel_size = |dataset: &CachedDataset| {
+			let metadata = dataset.metadata();
+			metadata.tile_span().to_radians() / metadata.resolution as f64 * EARTH_RADIUS
+		};
+		let dataset = &datasets[datasets
+			.iter()
+			.rposition(|x| texel_size(x) <= column_length)
+			.unwrap_or(0)];
+		let step = texel_size(dataset);
+		let along = (column_length / step).ceil().max(1.0);
+		let across = (corridor / step).ceil();
+		if columns as f64 * along * (across + 1.0) > ProfileOptions::MAX_SAMPLES as f64 {
+			return Err(ProfileError::TooManySamples);
+		}
+		let sampling = Sampling {
+			columns,
+			column_length,
+			along: along as usize,
+			across: across as usize,
+			corridor,
+		};
+
+		let points = sampling.points(&Path::new(&options.path));
+		let samples = dataset.elevations_at(&points)?;
+		let (elevations, water) = samples
+			.chunks(sampling.per_column())
+			.map(|column| {
+				let highest = column.iter().max_by(|a, b| a.height.total_cmp(&b.height)).unwrap();
+				(highest.height * FEET_PER_METER, highest.water)
+			})
+			.unzip();
+
+		let mut profile = Self {
+			elevations,
+			water,
+			column_length: (column_length / METERS_PER_NAUTICAL_MILE) as f32,
+			width: options.width,
+			height: options.height,
+			image: Vec::new(),
+		};
+		profile.image = profile.draw(options);
+
+		Ok(profile)
+	}
+
+	fn draw(&self, options: &ProfileOptions) -> Vec<u8> {
+		let (width, height) = (self.width as usize, self.height as usize);
+		let span = options.max_elevation - options.min_elevation;
+		let aircraft = ((options.max_elevation - options.altitude) / span * height as f32).floor();
+
+		let mut image = vec![0; width * height * 4];
+		for y in 0..height {
+			let elevation = options.max_elevation - span * (y as f32 + 0.5) / height as f32;
+			for x in 0..width {
+				let color = if y as f32 == aircraft {
+					AIRCRAFT
+				} else if elevation > self.elevations[x] {
+					SKY
+				} else if self.water[x] {
+					options.color_scheme.water
+				} else {
+					match options.mode {
+						RenderMode::Elevation => options.color_scheme.color_at(elevation),
+						RenderMode::Taws => taws_color(elevation - options.altitude),
+					}
+				};
+
+				let pixel = &mut image[(y * width + x) * 4..(y * width + x) * 4 + 4];
+				for (out, c) in pixel.iter_mut().zip(color) {
+					*out = (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+				}
+				pixel[3] = 255;
+			}
+		}
+
+		image
+	}
+}
+
+/// The TAWS band of terrain `relative` feet above the aircraft.
+fn taws_color(relative: f32) -> [f32; 3] {
+	if relative > 2000.0 {
+		TAWS_RED
+	} else if relative > -500.0 {
+		TAWS_YELLOW
+	} else if relative > -2000.0 {
+		TAWS_GREEN
+	} else {
+		TAWS_BELOW
+	}
+}
+
+/// Where the elevations of each column are sampled: `along` rows spread evenly over the column, of `across + 1`
+/// points spread evenly over the corridor.
+struct Sampling {
+	columns: usize,
+	/// The distance along the path covered by each column, in meters.
+	column_length: f64,
+	along: usize,
+	across: usize,
+	/// The width of the corridor, in meters.
+	corridor: f64,
+}
+
+impl Sampling {
+	fn per_column(&self) -> usize { self.along * (self.across + 1) }
+
+	/// The sample points along `path`, column by column.
+	fn points(&self, path: &Path) -> Vec<(f64, f64)> {
+		let mut points = Vec::with_capacity(self.columns * self.per_column());
+		for column in 0..self.columns {
+			for i in 0..self.along {
+				let distance = (column as f64 + (i as f64 + 0.5) / self.along as f64) * self.column_length;
+				let (center, bearing) = path.at(distance);
+				for j in 0..=self.across {
+					let offset = if self.across == 0 {
+						0.0
+					} else {
+						(j as f64 / self.across as f64 - 0.5) * self.corridor
+					};
+					points.push(great_circle_destination(center, bearing + FRAC_PI_2, offset));
+				}
+			}
+		}
+
+		points
+	}
+}
+
+/// The legs of a path, with the distance from the start of the path to the start of each.
+struct Path {
+	legs: Vec<Leg>,
+}
+
+struct Leg {
+	start: (f64, f64),
+	/// The initial bearing, in radians.
+	bearing: f64,
+	distance: f64,
+}
+
+impl Path {
+	/// `points` must not be empty.
+	fn new(points: &[LatLon]) -> Self {
+		let points: Vec<_> = points.iter().map(|x| (x.lat as f64, x.lon as f64)).collect();
+		let mut distance = 0.0;
+		let mut legs: Vec<_> = points
+			.windows(2)
+			.map(|leg| {
+				let ret = Leg {
+					start: leg[0],
+					bearing: initial_bearing(leg[0], leg[1]),
+					distance,
+				};
+				distance += great_circle_distance(leg[0], leg[1]);
+				ret
+			})
+			.collect();
+		if legs.is_empty() {
+			legs.push(Leg {
+				start: points[0],
+				bearing: 0.0,
+				distance: 0.0,
+			});
+		}
+
+		Self { legs }
+	}
+
+	/// The point `distance` meters along the path, and the bearing of the path there.
+	fn at(&self, distance: f64) -> ((f64, f64), f64) {
+		let leg = self
+			.legs
+			.iter()
+			.rev()
+			.find(|x| x.distance <= distance)
+			.unwrap_or(&self.legs[0]);
+		(
+			great_circle_destination(leg.start, leg.bearing, distance - leg.distance),
+			leg.bearing,
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use geo::{great_circle_distance, EARTH_RADIUS};
+
+	use super::*;
+
+	fn point(lat: f32, lon: f32) -> LatLon { LatLon { lat, lon } }
+
+	fn close(a: f64, b: f64) -> bool { (a - b).abs() < 1e-6 }
+
+	#[test]
+	fn single_point_runs_north() {
+		let path = Path::new(&[point(10.0, 20.0)]);
+
+		let ((lat, lon), bearing) = path.at(0.0);
+		assert!(close(lat, 10.0) && close(lon, 20.0));
+		assert_eq!(bearing, 0.0);
+
+		let ((lat, lon), _) = path.at(EARTH_RADIUS.to_radians());
+		assert!(close(lat, 11.0) && close(lon, 20.0));
+	}
+
+	#[test]
+	fn path_legs() {
+		let points = [point(0.0, 0.0), point(0.0, 1.0), point(1.0, 1.0)];
+		let path = Path::new(&points);
+		let first = great_circle_distance((0.0, 0.0), (0.0, 1.0));
+
+		let ((lat, lon), bearing) = path.at(first / 2.0);
+		assert!(close(lat, 0.0) && close(lon, 0.5));
+		assert!(close(bearing, FRAC_PI_2));
+
+		let ((lat, lon), bearing) = path.at(first + EARTH_RADIUS.to_radians() / 2.0);
+		assert!(close(lat, 0.5) && close(lon, 1.0));
+		assert!(close(bearing, 0.0));
+
+		// Past the end, the path continues along its last leg.
+		let ((lat, lon), _) = path.at(first + EARTH_RADIUS.to_radians() * 2.0);
+		assert!(close(lat, 2.0) && close(lon, 1.0));
+	}
+
+	#[test]
+	fn sampling() {
+		let sampling = Sampling {
+			columns: 4,
+			column_length: 1000.0,
+			along: 2,
+			across: 2,
+			corridor: 500.0,
+		};
+		let points = sampling.points(&Path::new(&[point(0.0, 0.0)]));
+		assert_eq!(points.len(), 4 * sampling.per_column());
+
+		let to_degrees = |meters: f64| (meters / EARTH_RADIUS).to_degrees();
+		for (column, points) in points.chunks(sampling.per_column()).enumerate() {
+			for (i, row) in points.chunks(3).enumerate() {
+				// Rows are in the middle of each half of the column, and span the corridor across the path.
+				let lat = to_degrees((column as f64 + (i as f64 + 0.5) / 2.0) * 1000.0);
+				assert!(row.iter().all(|x| close(x.0, lat)));
+				assert!(close(row[0].1, -to_degrees(250.0)));
+				assert!(close(row[1].1, 0.0));
+				assert!(close(row[2].1, to_degrees(250.0)));
+			}
+		}
+
+		let narrow = Sampling { across: 0, ..sampling };
+		let points = narrow.points(&Path::new(&[point(0.0, 0.0)]));
+		assert_eq!(points.len(), 4 * 2);
+		assert!(points.iter().all(|x| close(x.1, 0.0)));
+	}
+
+	#[test]
+	fn validation() {
+		let valid = || ProfileOptions {
+			path: vec![point(0.0, 0.0)],
+			..Default::default()
+		};
+		assert!(valid().validate().is_ok());
+
+		let invalid = [
+			ProfileOptions {
+				path: Vec::new(),
+				..valid()
+			},
+			ProfileOptions {
+				path: vec![point(f32::NAN, 0.0)],
+				..valid()
+			},
+			ProfileOptions { range: 0.0, ..valid() },
+			ProfileOptions {
+				corridor_width: f32::NAN,
+				..valid()
+			},
+			ProfileOptions { width: 0, ..valid() },
+			ProfileOptions {
+				height: ProfileOptions::MAX_IMAGE_SIZE + 1,
+				..valid()
+			},
+			ProfileOptions {
+				min_elevation: 1000.0,
+				max_elevation: 1000.0,
+				..valid()
+			},
+		];
+		for options in invalid {
+			assert!(options.validate().is_err());
+		}
+
+		assert!(matches!(Profile::new(&[], &valid()), Err(ProfileError::NoDatasets)));
+	}
+}
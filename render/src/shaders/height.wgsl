@@ -8,23 +8,62 @@ struct Uniform {
     [[align(16)]] vertical_diameter: f32;
     output_resolution_x: u32;
     output_resolution_y: u32;
-    tile_size: u32;
     heading: f32;
     altitude: f32;
+    // 0 for stored hillshades, 1 for screen-space.
+    shading: u32;
+    sun_azimuth: f32;
+    sun_zenith: f32;
+    multi_directional: u32;
+    // 0 for absolute elevation, 1 for TAWS.
+    mode: u32;
+    // Where the aircraft is on screen, from (0, 0) at the top left to (1, 1) at the bottom right.
+    center_offset: vec2<f32>;
+    // The radius of the mask in screen heights, or 0 if there is no mask.
+    mask_radius: f32;
+    // How far the mask extends either side of straight up, in radians.
+    mask_half_angle: f32;
+    // The south west corner of the alert map, in radians.
+    alert_origin: LatLon;
+    // The side of a cell of the alert map in radians, or 0 if there are no alerts.
+    alert_cell_size: f32;
 };
 
 struct TileStatus {
     values: array<u32>;
 };
 
+struct Lod {
+    tile_size: u32;
+    tiles_per_degree: u32;
+    // The angle covered by a texel, in radians.
+    texel_angle: f32;
+    width_band_latitudes: vec4<u32>;
+    width_band_shifts: vec4<u32>;
+};
+
+// Ordered from the finest to the coarsest.
+struct Lods {
+    values: array<Lod>;
+};
+
 [[group(0), binding(0)]]
 var<uniform> uniforms: Uniform;
 [[group(0), binding(1)]]
-var tile_map: texture_2d<u32>;
+var tile_map: texture_2d_array<u32>;
 [[group(0), binding(2)]]
 var<storage, read_write> tile_status: TileStatus;
 [[group(0), binding(3)]]
-var tile_atlas: texture_2d<u32>;
+var tile_atlas: texture_2d_array<u32>;
+[[group(0), binding(4)]]
+var<storage, read> lods: Lods;
+[[group(0), binding(5)]]
+var hillshade_atlas: texture_2d_array<u32>;
+
+struct HeightOutput {
+    [[location(0)]] height: u32;
+    [[location(1)]] hillshade: f32;
+};
 
 
 
@@ -32,15 +71,20 @@ fn degrees(radians: f32) -> f32 {
     return radians * 57.295779513082322865;
 }
 
-fn project(uv: vec2<f32>) -> LatLon {
+// The offset of `uv` from the aircraft, in screen heights with y up.
+fn from_aircraft(uv: vec2<f32>) -> vec2<f32> {
     let aspect_ratio = f32(uniforms.output_resolution_x) / f32(uniforms.output_resolution_y);
+    let anchor = vec2<f32>(uniforms.center_offset.x, 1.0 - uniforms.center_offset.y);
+    let offset_uv = uv - anchor;
+    return vec2<f32>(offset_uv.x * aspect_ratio, offset_uv.y);
+}
+
+fn project(uv: vec2<f32>) -> LatLon {
     let headsin = sin(uniforms.heading);
     let headcos = cos(uniforms.heading);
-    let offset_uv = vec2<f32>(uv.x - 0.5, uv.y - 0.5);
-    let scaled_uv = vec2<f32>(offset_uv.x * aspect_ratio, offset_uv.y);
+    let scaled_uv = from_aircraft(uv);
     let rotated_uv = vec2<f32>(scaled_uv.x * headcos - scaled_uv.y * headsin, scaled_uv.x * headsin + scaled_uv.y * headcos);
-    let uv = vec2<f32>(rotated_uv.x + 0.5, rotated_uv.y + 0.5);
-    let xy = (uv - vec2<f32>(0.5, 0.5)) * uniforms.vertical_diameter;
+    let xy = rotated_uv * uniforms.vertical_diameter;
 
     let latsin = sin(uniforms.map_center.lat);
     let latcos = cos(uniforms.map_center.lat);
@@ -54,46 +98,78 @@ fn project(uv: vec2<f32>) -> LatLon {
     return LatLon(lat, lon);
 }
 
-fn sample_globe(lat: f32, lon: f32) -> u32 {
-    let tile_loc = vec2<u32>(u32(lon), u32(lat));
-    let index = tile_loc.y * 360u + tile_loc.x;
+// The width of the tiles of `lod` in the row `tile_lat` (from the south pole), narrowed by the width bands.
+fn tile_width(tile_lat: u32, lod: u32) -> u32 {
+    let tiles_per_degree = lods.values[lod].tiles_per_degree;
+    let lat = i32(tile_lat) - 90 * i32(tiles_per_degree);
+    var edge = f32(lat);
+    if (lat < 0) {
+        edge = -f32(lat + 1);
+    }
+    edge = edge / f32(tiles_per_degree);
+
+    var shift = 0u;
+    for (var i = 0; i < 4; i = i + 1) {
+        if (edge >= f32(lods.values[lod].width_band_latitudes[i])) {
+            shift = max(shift, lods.values[lod].width_band_shifts[i]);
+        }
+    }
+
+    return lods.values[lod].tile_size >> shift;
+}
+
+// The height of the texel at `lat`, `lon`, and its hillshade.
+fn sample_globe(lat: f32, lon: f32, lod: u32) -> vec2<u32> {
+    let tpd = f32(lods.values[lod].tiles_per_degree);
+    let tile_loc = vec2<u32>(u32(lon * tpd), u32(lat * tpd));
+    let grid = vec2<u32>(textureDimensions(tile_map));
+    let index = (lod * grid.y + tile_loc.y) * grid.x + tile_loc.x;
     tile_status.values[index] = 1u;
-    let tile_offset = vec2<i32>(textureLoad(tile_map, vec2<i32>(tile_loc), 0)).xy;
+    // The layer of the tile is in the high 16 bits of y.
+    let tile_offset = textureLoad(tile_map, vec2<i32>(tile_loc), i32(lod), 0).xy;
+    let tile_layer = i32(tile_offset.y >> 16u);
+    let tile_offset = vec2<i32>(i32(tile_offset.x), i32(tile_offset.y & 0xffffu));
 
     let atlas_dimensions = textureDimensions(tile_atlas, 0);
-    let not_found = tile_offset.x == i32(atlas_dimensions.x);
-    let unloaded = tile_offset.y == i32(atlas_dimensions.y);
+    let not_found = tile_offset.x == atlas_dimensions.x;
+    let unloaded = tile_offset.y == atlas_dimensions.y;
 
     if (not_found) {
-        return 1u << 15u;
+        return vec2<u32>(1u << 15u, 0u);
     } else if (unloaded) {
-        return ~0u;
+        return vec2<u32>(~0u, 0u);
     } else {
-        let tile_uv = vec2<f32>(lon - floor(lon), 1.0 - (lat - floor(lat)));
-        let pixel = vec2<f32>(tile_offset) + tile_uv * f32(uniforms.tile_size);
+        let tile_uv = vec2<f32>(fract(lon * tpd), 1.0 - fract(lat * tpd));
+        let tile_size = vec2<f32>(f32(tile_width(tile_loc.y, lod)), f32(lods.values[lod].tile_size));
+        let pixel = vec2<i32>(vec2<f32>(tile_offset) + tile_uv * tile_size);
 
-        return textureLoad(tile_atlas, vec2<i32>(pixel), 0).x;
+        let height = textureLoad(tile_atlas, pixel, tile_layer, 0).x;
+        let hillshade = textureLoad(hillshade_atlas, pixel, tile_layer, 0).x;
+        return vec2<u32>(height, hillshade);
     }
 }
 
-[[stage(fragment)]]
-fn main([[location(0)]] uv: vec2<f32>) -> [[location(0)]] u32 {
-    let rad_position = project(uv);
-    let lat = degrees(rad_position.lat) + 90.0;
-    var lon = (degrees(rad_position.lon) + 180.0) % 360.0;
-    if (lon < 0.0) {
-        lon = lon + 360.0;
-    }
-
-    let tile_uv = vec2<f32>(lon - floor(lon), 1.0 - (lat - floor(lat)));
-    let pixel = tile_uv * f32(uniforms.tile_size);
+// Bilinearly sample the heights and hillshade of one LOD. The height is 0xffff if any of its tiles are still loading.
+fn sample_lod(lat: f32, lon: f32, lod: u32) -> HeightOutput {
+    let tpd = f32(lods.values[lod].tiles_per_degree);
+    let tile_uv = vec2<f32>(fract(lon * tpd), 1.0 - fract(lat * tpd));
+    let tile_size = vec2<f32>(f32(tile_width(u32(lat * tpd), lod)), f32(lods.values[lod].tile_size));
+    let pixel = tile_uv * tile_size;
     let pixel_offset = pixel - floor(pixel);
 
-    let delta = 1.0 / f32(uniforms.tile_size);
-    let x = sample_globe(lat, lon);
-    let y = sample_globe(lat, lon + delta);
-    let z = sample_globe(lat - delta, lon);
-    let w = sample_globe(lat - delta, lon + delta);
+    let delta = 1.0 / (tile_size * tpd);
+    let xs = sample_globe(lat, lon, lod);
+    let ys = sample_globe(lat, lon + delta.x, lod);
+    let zs = sample_globe(lat - delta.y, lon, lod);
+    let ws = sample_globe(lat - delta.y, lon + delta.x, lod);
+    let x = xs.x;
+    let y = ys.x;
+    let z = zs.x;
+    let w = ws.x;
+
+    if (x == ~0u || y == ~0u || z == ~0u || w == ~0u) {
+        return HeightOutput(0xffffu, 0.0);
+    }
 
     let xh = f32(~(1u << 15u) & x);
     let yh = f32(~(1u << 15u) & y);
@@ -113,5 +189,73 @@ fn main([[location(0)]] uv: vec2<f32>) -> [[location(0)]] u32 {
     let xh_lerp = mix(zw, ww, pixel_offset.x);
     let is_water = select(0u, 1u, mix(xl_lerp, xh_lerp, pixel_offset.y) > 0.5);
 
-    return (is_water << 15u) | height;
+    let xl_lerp = mix(f32(xs.y), f32(ys.y), pixel_offset.x);
+    let xh_lerp = mix(f32(zs.y), f32(ws.y), pixel_offset.x);
+    let hillshade = mix(xl_lerp, xh_lerp, pixel_offset.y) / 255.0;
+
+    return HeightOutput((is_water << 15u) | height, hillshade);
+}
+
+// The largest angle covered by the fragment at `position`, in radians.
+fn footprint(position: LatLon) -> f32 {
+    let tau = 6.28318530717958647692;
+    let dx = vec2<f32>(dpdx(position.lat), dpdx(position.lon));
+    let dy = vec2<f32>(dpdy(position.lat), dpdy(position.lon));
+
+    // Longitude wraps around at the antimeridian, and meridians converge towards the poles.
+    let lon_scale = cos(position.lat);
+    let dx = vec2<f32>(dx.x, (dx.y - tau * round(dx.y / tau)) * lon_scale);
+    let dy = vec2<f32>(dy.x, (dy.y - tau * round(dy.y / tau)) * lon_scale);
+
+    return max(length(dx), length(dy));
+}
+
+// If `uv` is outside the circle or arc around the aircraft that the map is drawn in.
+fn masked(uv: vec2<f32>) -> bool {
+    if (uniforms.mask_radius <= 0.0) {
+        return false;
+    }
+
+    let xy = from_aircraft(uv);
+    return length(xy) > uniforms.mask_radius || atan2(abs(xy.x), xy.y) > uniforms.mask_half_angle;
+}
+
+[[stage(fragment)]]
+fn main([[location(0)]] uv: vec2<f32>) -> HeightOutput {
+    let rad_position = project(uv);
+    let footprint = footprint(rad_position);
+    // Masked fragments are marked with 0xfffe, without sampling any tiles. Derivatives are taken first, so they stay
+    // in uniform control flow.
+    if (masked(uv)) {
+        return HeightOutput(0xfffeu, 0.0);
+    }
+
+    let lat = degrees(rad_position.lat) + 90.0;
+    var lon = (degrees(rad_position.lon) + 180.0) % 360.0;
+    if (lon < 0.0) {
+        lon = lon + 360.0;
+    }
+
+    // Pick the coarsest LOD that still has a texel for every fragment.
+    let lod_count = arrayLength(&lods.values);
+    var lod = 0u;
+    for (var i = lod_count; i > 0u; i = i - 1u) {
+        if (footprint >= lods.values[i - 1u].texel_angle) {
+            lod = i - 1u;
+            break;
+        }
+    }
+
+    // The coarsest LOD is always sampled, so its tiles stay resident for the others to fall back to.
+    let coarse = sample_lod(lat, lon, lod_count - 1u);
+    if (lod == lod_count - 1u) {
+        return coarse;
+    }
+
+    // Tiles that are still loading fall back to the coarsest LOD, and to a placeholder if that is loading too.
+    let fine = sample_lod(lat, lon, lod);
+    if (fine.height == 0xffffu) {
+        return coarse;
+    }
+    return fine;
 }
@@ -8,96 +8,137 @@ struct Uniform {
     [[align(16)]] vertical_diameter: f32;
     output_resolution_x: u32;
     output_resolution_y: u32;
-    tile_size: u32;
     heading: f32;
     altitude: f32;
+    // 0 for stored hillshades, 1 for screen-space.
+    shading: u32;
+    sun_azimuth: f32;
+    sun_zenith: f32;
+    multi_directional: u32;
+    // 0 for absolute elevation, 1 for TAWS.
+    mode: u32;
+    // Where the aircraft is on screen, from (0, 0) at the top left to (1, 1) at the bottom right.
+    center_offset: vec2<f32>;
+    // The radius of the mask in screen heights, or 0 if there is no mask.
+    mask_radius: f32;
+    // How far the mask extends either side of straight up, in radians.
+    mask_half_angle: f32;
+    // The south west corner of the alert map, in radians.
+    alert_origin: LatLon;
+    // The side of a cell of the alert map in radians, or 0 if there are no alerts.
+    alert_cell_size: f32;
+};
+
+// The color of terrain up to `elevation` feet.
+struct ColorStop {
+    color: vec3<f32>;
+    elevation: f32;
+};
+
+struct ColorScheme {
+    water: vec4<f32>;
+    unknown: vec4<f32>;
+    stop_count: u32;
+    interpolated: u32;
+    stops: array<ColorStop, 64>;
 };
 
 [[group(0), binding(0)]]
 var<uniform> uniforms: Uniform;
 [[group(0), binding(1)]]
 var heightmap: texture_2d<u32>;
+[[group(0), binding(2)]]
+var hillshade: texture_2d<f32>;
+[[group(0), binding(3)]]
+var<uniform> colors: ColorScheme;
+[[group(0), binding(4)]]
+var alert_map: texture_2d<u32>;
 
-var<private> l500: vec3<f32> = vec3<f32>(0.17, 0.31, 0.16);
-var<private> l1000: vec3<f32> = vec3<f32>(0.22, 0.36, 0.19);
-var<private> l2000: vec3<f32> = vec3<f32>(0.33, 0.46, 0.21);
-var<private> l3000: vec3<f32> = vec3<f32>(0.41, 0.51, 0.28);
-var<private> l4000: vec3<f32> = vec3<f32>(0.49, 0.5, 0.3);
-var<private> l5000: vec3<f32> = vec3<f32>(0.47, 0.52, 0.26);
-var<private> l6000: vec3<f32> = vec3<f32>(0.46, 0.49, 0.29);
-var<private> l7000: vec3<f32> = vec3<f32>(0.41, 0.43, 0.24);
-var<private> l8000: vec3<f32> = vec3<f32>(0.45, 0.4, 0.22);
-var<private> l9000: vec3<f32> = vec3<f32>(0.4, 0.35, 0.18);
-var<private> l10000: vec3<f32> = vec3<f32>(0.33, 0.25, 0.12);
-var<private> l11000: vec3<f32> = vec3<f32>(0.27, 0.21, 0.11);
-var<private> l12000: vec3<f32> = vec3<f32>(0.31, 0.3, 0.25);
-var<private> l13000: vec3<f32> = vec3<f32>(0.35, 0.38, 0.33);
-var<private> l15000: vec3<f32> = vec3<f32>(0.43, 0.45, 0.43);
-var<private> l17000: vec3<f32> = vec3<f32>(0.48, 0.48, 0.46);
-var<private> l19000: vec3<f32> = vec3<f32>(0.51, 0.53, 0.52);
-var<private> l21000: vec3<f32> = vec3<f32>(0.51, 0.55, 0.55);
-var<private> l33000: vec3<f32> = vec3<f32>(0.56, 0.6, 0.6);
-var<private> unknown_terrain: vec3<f32> = vec3<f32>(0.41, 0.15, 0.42);
-var<private> water: vec3<f32> = vec3<f32>(0.01,0.09,0.31);
+var<private> loading: vec3<f32> = vec3<f32>(0.1, 0.1, 0.1);
 var<private> taws_yellow: vec3<f32> = vec3<f32>(0.99, 0.93, 0.09);
 var<private> taws_red: vec3<f32> = vec3<f32>(0.93, 0.12, 0.14);
+var<private> taws_green: vec3<f32> = vec3<f32>(0.11, 0.72, 0.16);
+// A 4x4 ordered dither matrix, so the dots of each density are spread evenly.
+var<private> dither: array<u32, 16> = array<u32, 16>(0u, 8u, 2u, 10u, 12u, 4u, 14u, 6u, 3u, 11u, 1u, 9u, 15u, 7u, 13u, 5u);
 
 fn radians(degrees: f32) -> f32 {
     return degrees * 0.0174533;
 }
 
+// The offset of `uv` from the aircraft, in screen heights with y up.
+fn from_aircraft(uv: vec2<f32>) -> vec2<f32> {
+    let aspect_ratio = f32(uniforms.output_resolution_x) / f32(uniforms.output_resolution_y);
+    let anchor = vec2<f32>(uniforms.center_offset.x, 1.0 - uniforms.center_offset.y);
+    let offset_uv = uv - anchor;
+    return vec2<f32>(offset_uv.x * aspect_ratio, offset_uv.y);
+}
+
+fn project(uv: vec2<f32>) -> LatLon {
+    let headsin = sin(uniforms.heading);
+    let headcos = cos(uniforms.heading);
+    let scaled_uv = from_aircraft(uv);
+    let rotated_uv = vec2<f32>(scaled_uv.x * headcos - scaled_uv.y * headsin, scaled_uv.x * headsin + scaled_uv.y * headcos);
+    let xy = rotated_uv * uniforms.vertical_diameter;
+
+    let latsin = sin(uniforms.map_center.lat);
+    let latcos = cos(uniforms.map_center.lat);
+    let c = sqrt(xy.x * xy.x + xy.y * xy.y);
+    let csin = sin(c);
+    let ccos = cos(c);
+
+    let lat = asin(ccos * latsin + xy.y * csin * latcos / c);
+    let lon = uniforms.map_center.lon + atan2(xy.x * csin, c * latcos * ccos - xy.y * latsin * csin);
+
+    return LatLon(lat, lon);
+}
+
+// The level of the terrain alert at `uv`: 0 if it is clear, 1 for a caution, and 2 for a warning.
+fn alert_level(uv: vec2<f32>) -> u32 {
+    if (uniforms.alert_cell_size <= 0.0) {
+        return 0u;
+    }
+
+    let tau = 6.28318530717958647692;
+    let position = project(uv);
+    let lon = position.lon - uniforms.alert_origin.lon;
+    let cell = vec2<f32>(lon - tau * floor(lon / tau), position.lat - uniforms.alert_origin.lat) / uniforms.alert_cell_size;
+    let size = vec2<f32>(textureDimensions(alert_map));
+    if (cell.x < 0.0 || cell.y < 0.0 || cell.x >= size.x || cell.y >= size.y) {
+        return 0u;
+    }
+    return textureLoad(alert_map, vec2<i32>(cell), 0).x;
+}
+
 fn map_height(height: u32) -> vec3<f32> {
     let feet = f32(i32(height) - 500) * 3.28084;
     if (feet > uniforms.altitude + 2000.0) {
         return taws_red;
     } else if (feet > uniforms.altitude - 500.0) {
         return taws_yellow;
-    } else if (feet < 500.0) {
-        return l500;
-    } else {
-        switch (i32(feet / 1000.0)) {
-            case 0: { return l1000; }
-            case 1: { return l2000; }
-            case 2: { return l3000; }
-            case 3: { return l4000; }
-            case 4: { return l5000; }
-            case 5: { return l6000; }
-            case 6: { return l7000; }
-            case 7: { return l8000; }
-            case 8: { return l9000; }
-            case 9: { return l10000; }
-            case 10: { return l11000; }
-            case 11: { return l12000; }
-            case 12: { return l13000; }
-            case 13: { return l15000; }
-            case 14: { return l15000; }
-            case 15: { return l17000; }
-            case 16: { return l17000; }
-            case 17: { return l19000; }
-            case 18: { return l19000; }
-            case 19: { return l21000; }
-            case 20: { return l21000; }
-            case 21: { return l33000; }
-            case 22: { return l33000; }
-            case 23: { return l33000; }
-            case 24: { return l33000; }
-            case 25: { return l33000; }
-            case 26: { return l33000; }
-            case 27: { return l33000; }
-            case 28: { return l33000; }
-            case 29: { return l33000; }
-            case 30: { return l33000; }
-            case 31: { return l33000; }
-            case 32: { return l33000; }
-            default: { return unknown_terrain; }
+    }
+
+    for (var i = 0u; i < colors.stop_count; i = i + 1u) {
+        let stop = colors.stops[i];
+        if (feet < stop.elevation) {
+            if (colors.interpolated == 0u || i == 0u) {
+                return stop.color;
+            }
+
+            let prev = colors.stops[i - 1u];
+            return mix(prev.color, stop.color, (feet - prev.elevation) / (stop.elevation - prev.elevation));
         }
     }
+
+    return colors.unknown.rgb;
+}
+
+fn light(slope: f32, aspect: f32, azimuth: f32) -> f32 {
+    let zenith = uniforms.sun_zenith;
+    return clamp(cos(zenith) * cos(slope) + sin(zenith) * sin(slope) * cos(azimuth - aspect), 0.0, 1.0);
 }
 
 fn calculate_hillshade(height: f32) -> f32 {
     let m_per_pixel = (uniforms.vertical_diameter / f32(uniforms.output_resolution_y)) * 6371000.0;
-    let zenith = radians(45.0);
-    let azimuth = radians(135.0);
 
     let dzdx = dpdx(height); // * m_per_pixel;
     let dzdy = dpdy(height); // * m_per_pixel;
@@ -105,11 +146,60 @@ fn calculate_hillshade(height: f32) -> f32 {
 
     let aspect = atan2(dzdy, -dzdx);
 
-    return clamp(cos(zenith) * cos(slope) + sin(zenith) * sin(slope) * cos(azimuth - aspect), 0.0, 1.0);
+    // Aspects are counter-clockwise from the right of the screen, which is rotated by the heading.
+    let azimuth = radians(450.0) - uniforms.sun_azimuth - uniforms.heading;
+    if (uniforms.multi_directional == 1u) {
+        return (light(slope, aspect, azimuth + radians(67.5))
+            + light(slope, aspect, azimuth + radians(22.5))
+            + light(slope, aspect, azimuth - radians(22.5))
+            + light(slope, aspect, azimuth - radians(67.5))) / 4.0;
+    }
+    return light(slope, aspect, azimuth);
+}
+
+// If the dot at `pixel` is drawn in a fill of `density`, from 0 to 1. Dots are 2x2 pixels.
+fn dot_fill(pixel: vec2<i32>, density: f32) -> bool {
+    let cell = (vec2<u32>(pixel) / 2u) % 4u;
+    return f32(dither[cell.y * 4u + cell.x]) < density * 16.0;
+}
+
+// The TAWS color of terrain relative to the aircraft: dots of red, yellow, and green with falling density as the
+// terrain gets further below, and black more than 2000 feet below.
+fn map_taws(height: u32, is_water: u32, pixel: vec2<i32>) -> vec3<f32> {
+    var feet = f32(i32(height & 0x7fffu) - 500) * 3.28084;
+    if (is_water == 1u) {
+        feet = max(feet, 0.0);
+    }
+    let relative = feet - uniforms.altitude;
+
+    var color = vec3<f32>(0.0);
+    var density = 0.0;
+    if (relative > 2000.0) {
+        color = taws_red;
+        density = 0.5;
+    } else if (relative > 1000.0) {
+        color = taws_yellow;
+        density = 0.5;
+    } else if (relative > -500.0) {
+        color = taws_yellow;
+        density = 0.25;
+    } else if (relative > -1000.0) {
+        color = taws_green;
+        density = 0.5;
+    } else if (relative > -2000.0) {
+        color = taws_green;
+        density = 0.16;
+    }
+
+    if (dot_fill(pixel, density)) {
+        return color;
+    }
+    return vec3<f32>(0.0);
 }
 
 [[stage(fragment)]]
 fn main([[location(0)]] uv: vec2<f32>) -> [[location(0)]] vec4<f32> {
+    let alert = alert_level(uv);
     let uv = vec2<f32>(uv.x, 1.0 - uv.y);
     let pixel = vec2<i32>(uv * vec2<f32>(f32(uniforms.output_resolution_x), f32(uniforms.output_resolution_y)));
 
@@ -117,10 +207,29 @@ fn main([[location(0)]] uv: vec2<f32>) -> [[location(0)]] vec4<f32> {
     let is_water = (height >> 15u) & 1u;
 
     var ret: vec3<f32>;
-    if (is_water == 1u) {
-        ret = water;
+    if (height == 0xffffu) {
+        ret = loading;
+    } else if (height == 0xfffeu) {
+        ret = vec3<f32>(0.0);
+    } else if (uniforms.mode == 1u) {
+        ret = map_taws(height, is_water, pixel);
+    } else if (is_water == 1u) {
+        ret = colors.water.rgb;
     } else {
-        ret = map_height(height) * mix(0.4, 1.0, calculate_hillshade(f32(height)));
+        var shade = textureLoad(hillshade, pixel, 0).x;
+        if (uniforms.shading == 1u) {
+            shade = calculate_hillshade(f32(height));
+        }
+        ret = map_height(height) * mix(0.4, 1.0, shade);
+    }
+
+    // Alert cells are drawn solid over the terrain, but not over the mask or placeholders.
+    if (height < 0xfffeu) {
+        if (alert == 2u) {
+            ret = taws_red;
+        } else if (alert == 1u) {
+            ret = taws_yellow;
+        }
     }
     return vec4<f32>(pow(ret, vec3<f32>(2.2)), 1.0);
 }
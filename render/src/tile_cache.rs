@@ -1,29 +1,39 @@
-use std::{num::NonZeroU32, path::PathBuf};
+use std::{
+	cmp::Reverse,
+	collections::{HashSet, VecDeque},
+	num::NonZeroU32,
+	path::PathBuf,
+	sync::{
+		mpsc::{Receiver, TryRecvError},
+		Arc,
+	},
+};
 
-use geo::{Dataset, LoadError};
+use geo::{CachedDataset, Dataset, DecodedTile, LoadError, Tile, TileLayers};
 use wgpu::{
 	Buffer,
 	BufferDescriptor,
 	BufferUsages,
 	Device,
 	Extent3d,
-	ImageCopyTexture,
 	ImageDataLayout,
 	Maintain,
 	MapMode,
-	Origin3d,
 	Queue,
 	Texture,
-	TextureAspect,
 	TextureDescriptor,
 	TextureDimension,
 	TextureFormat,
 	TextureUsages,
 	TextureView,
 	TextureViewDescriptor,
+	TextureViewDimension,
 };
 
-use crate::range::radians_per_pixel;
+use crate::{
+	atlas::{Atlas, AtlasStats, TileOffset, TileStorage},
+	range::radians_per_pixel,
+};
 
 pub enum UploadStatus {
 	Uploads,
@@ -32,69 +42,137 @@ pub enum UploadStatus {
 	AtlasFull,
 }
 
+/// The parameters of one LOD, as laid out in the LOD buffer.
 #[repr(C)]
-#[derive(Copy, Clone, Default, PartialEq, Eq)]
-struct TileOffset {
-	x: u32,
-	y: u32,
+#[derive(Copy, Clone, Default)]
+struct LodData {
+	tile_size: u32,
+	tiles_per_degree: u32,
+	/// The angle covered by a texel, in radians.
+	texel_angle: f32,
+	_pad: u32,
+	width_band_latitudes: [u32; 4],
+	width_band_shifts: [u32; 4],
 }
 
+/// Tiles from every dataset, keyed by (lod, lat, lon). The shader picks a LOD for each fragment, so tiles of several
+/// LODs are resident at once.
+///
+/// Each LOD has a layer in the tile map, and a same-sized range of the tile status buffer. Layers are as large as the
+/// grid of the finest LOD, and coarser LODs only use a corner of theirs.
 pub struct TileCache {
+	datasets: Vec<Arc<CachedDataset>>,
 	tile_map: Texture,
 	tile_map_view: TextureView,
 	tile_status: Buffer,
+	lods: Buffer,
 	atlas: Atlas,
 	tiles: Vec<TileOffset>,
+	/// The frame each tile was last in view in. Tiles that went out of view stay resident until their space is needed.
+	last_used: Vec<u64>,
+	frame: u64,
+	/// The number of tiles along longitude and latitude in each layer of the tile map.
+	grid: (u32, u32),
+	/// Indices of tiles that are being decoded in the background.
+	requested: HashSet<usize>,
+	/// Indices of tiles in view that did not fit in the full atlas, or were evicted from it to make space for coarser
+	/// tiles. They are not requested again until a resident tile goes out of view or the atlas grows, so that a full
+	/// atlas doesn't keep decoding them, and they don't count as loading.
+	parked: HashSet<usize>,
+	loading: Vec<(usize, Receiver<DecodedTile<Arc<Tile>>>)>,
+	/// Decoded tiles and their LODs, waiting for upload budget.
+	ready: VecDeque<(usize, DecodedTile<Arc<Tile>>)>,
 }
 
 impl TileCache {
-	pub fn new(device: &Device, datasets: Vec<PathBuf>) -> Result<Self, LoadError> {
-		let tile_map = device.create_texture(&TextureDescriptor {
-			label: Some("Tile Map"),
-			size: Extent3d {
-				width: 360,
-				height: 180,
-				depth_or_array_layers: 1,
-			},
-			mip_level_count: 1,
-			sample_count: 1,
-			dimension: TextureDimension::D2,
-			format: TextureFormat::Rg32Uint,
-			usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+	/// The memory budget for decoded tiles, so tiles that were just evicted from the atlas can be re-uploaded without
+	/// decoding them again. It is split evenly between the datasets.
+	const DECODED_CACHE_SIZE: usize = 512 * 1024 * 1024;
+
+	/// Datasets must be ordered from the finest to the coarsest.
+	pub fn new(device: &Device, datasets: Vec<PathBuf>, storage: TileStorage) -> Result<Self, LoadError> {
+		let capacity = Self::DECODED_CACHE_SIZE / datasets.len().max(1);
+		let datasets: Result<Vec<_>, LoadError> = datasets
+			.into_iter()
+			.map(|dir| Dataset::load(&dir).map(|x| Arc::new(CachedDataset::new(x, capacity))))
+			.collect();
+		let datasets = datasets?;
+
+		let lods: Vec<_> = datasets
+			.iter()
+			.map(|x| {
+				let metadata = x.metadata();
+				let mut lod = LodData {
+					tile_size: metadata.resolution as _,
+					tiles_per_degree: metadata.tiles_per_degree as _,
+					texel_angle: radians_per_pixel(
+						metadata.resolution as _,
+						(metadata.tile_span() as f32).to_radians(),
+					),
+					..Default::default()
+				};
+				for (i, band) in metadata.width_bands.into_iter().enumerate() {
+					lod.width_band_latitudes[i] = band.latitude as _;
+					lod.width_band_shifts[i] = band.shift as _;
+				}
+				lod
+			})
+			.collect();
+		let lod_buffer = device.create_buffer(&BufferDescriptor {
+			label: Some("LOD Buffer"),
+			size: (lods.len() * std::mem::size_of::<LodData>()) as u64,
+			usage: BufferUsages::STORAGE,
+			mapped_at_creation: true,
 		});
-		let tile_map_view = tile_map.create_view(&TextureViewDescriptor {
-			label: Some("Tile Map View"),
-			..Default::default()
+		lod_buffer.slice(..).get_mapped_range_mut().copy_from_slice(unsafe {
+			std::slice::from_raw_parts(lods.as_ptr() as _, lods.len() * std::mem::size_of::<LodData>())
 		});
+		lod_buffer.unmap();
 
-		let tile_status = device.create_buffer(&BufferDescriptor {
-			label: Some("Tile Status"),
-			size: 360 * 180 * 4,
-			usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ | BufferUsages::STORAGE,
-			mapped_at_creation: false,
-		});
+		let grid = datasets
+			.iter()
+			.map(|x| (x.metadata().lon_tiles() as u32, x.metadata().lat_tiles() as u32))
+			.max()
+			.unwrap();
+		let (tile_map, tile_map_view, tile_status) = Self::make_tile_map(device, grid, datasets.len() as u32);
 
-		let atlas = Atlas::new(device, datasets)?;
+		let res = datasets.iter().map(|x| x.metadata().resolution as u32).max().unwrap();
+		let atlas = Atlas::new(device, storage, res);
 
+		let tiles = (grid.0 * grid.1) as usize * datasets.len();
 		Ok(Self {
+			tiles: vec![atlas.unloaded(); tiles],
+			last_used: vec![0; tiles],
+			frame: 0,
+			datasets,
 			tile_map,
 			tile_map_view,
 			tile_status,
-			tiles: vec![atlas.unloaded(); 360 * 180],
+			lods: lod_buffer,
 			atlas,
+			grid,
+			requested: HashSet::new(),
+			parked: HashSet::new(),
+			loading: Vec::new(),
+			ready: VecDeque::new(),
 		})
 	}
 
-	pub fn populate_tiles(&mut self, device: &Device, queue: &Queue, height: u32, vertical_angle: f32) -> UploadStatus {
+	/// Request the tiles used in the last frame from the background decoders, and upload at most `upload_budget` of
+	/// the decoded ones. Tiles of the coarsest LOD are uploaded first, since the others fall back to them.
+	///
+	/// When the atlas is full, tiles that are out of view are evicted first, then the atlas grows, and only then are
+	/// finer tiles in view evicted to make space for coarser ones. Tiles that still don't fit are dropped, and drawn
+	/// from coarser LODs until there is space.
+	pub fn populate_tiles(&mut self, device: &Device, queue: &Queue, upload_budget: usize) -> UploadStatus {
 		tracy::zone!("Tile Population");
 
-		let radians_per_pixel = radians_per_pixel(height as _, vertical_angle);
-
-		if self.atlas.needs_clear(radians_per_pixel) {
-			self.clear(radians_per_pixel);
-		}
-
+		self.frame += 1;
 		let mut ret = UploadStatus::NoUploads;
+		let mut resized = false;
+		let mut full = false;
+		// If the tile map must be written, which is whenever any offset changed, even if the atlas filled up.
+		let mut changed = false;
 		{
 			let _ = self.tile_status.slice(..).map_async(MapMode::Read);
 
@@ -106,285 +184,318 @@ impl TileCache {
 			let buf = self.tile_status.slice(..).get_mapped_range();
 			let used = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u32, buf.len() / 4) };
 
-			'outer: for lon in 0..360 {
-				for lat in 0..180 {
-					let index = (lat * 360 + lon) as usize;
-					let offset = &mut self.tiles[index];
-					if used[index] == 0 {
-						if *offset != self.atlas.unloaded() && *offset != self.atlas.not_found() {
-							self.atlas.return_tile(*offset);
-							*offset = self.atlas.unloaded();
-						}
-						continue;
-					} else if *offset != self.atlas.unloaded() {
-						continue;
+			let (unloaded, not_found) = (self.atlas.unloaded(), self.atlas.not_found());
+			let mut released = false;
+			let mut requests = vec![Vec::new(); self.datasets.len()];
+			for (index, (&used, offset)) in used.iter().zip(self.tiles.iter()).enumerate() {
+				if used == 0 {
+					// Its space can be reclaimed now, so the parked tiles get another chance.
+					released |= self.last_used[index] + 1 == self.frame && *offset != unloaded && *offset != not_found;
+					if !self.parked.is_empty() {
+						self.parked.remove(&index);
 					}
+					continue;
+				}
 
-					ret = UploadStatus::Uploads;
-					let lon = lon as i16 - 180;
-					let lat = lat as i16 - 90;
-					let tile = {
-						tracy::zone!("Load Tile");
-
-						let dataset = &self.atlas.datasets[self.atlas.curr_dataset];
-						if let Some(data) = dataset.get_tile(lat, lon) {
-							match data {
-								Ok(x) => x,
-								Err(e) => {
-									log::error!("Error loading tile: {:?}", e);
-									continue;
-								},
-							}
-						} else {
-							*offset = self.atlas.not_found();
-							continue;
-						}
-					};
-
-					self.tiles[index] = if let Some(offset) = self.atlas.upload_tile(queue, &tile.0, &tile.1) {
-						offset
-					} else if self.atlas.collect_tiles(used, &mut self.tiles, index) {
-						self.atlas
-							.upload_tile(queue, &tile.0, &tile.1)
-							.expect("Tile GC returned None when it had to be Some")
-					} else {
-						if self.atlas.recreate_atlas(device) {
-							self.tiles.fill(self.atlas.unloaded());
-							ret = UploadStatus::Resized;
-						} else {
-							ret = UploadStatus::AtlasFull;
-						}
-						break 'outer;
-					};
+				self.last_used[index] = self.frame;
+				if *offset == unloaded && !self.parked.contains(&index) && self.requested.insert(index) {
+					let (lod, lat, lon) = Self::key(&self.datasets, self.grid, index);
+					requests[lod].push((lat, lon));
+				}
+			}
+			if released {
+				self.parked.clear();
+			}
+			for (lod, requests) in requests.iter().enumerate().rev() {
+				if !requests.is_empty() {
+					self.loading
+						.push((lod, self.datasets[lod].decode_tiles(requests, TileLayers::all())));
 				}
 			}
-		}
 
-		self.tile_status.unmap();
+			{
+				tracy::zone!("Receive Tiles");
+				let ready = &mut self.ready;
+				self.loading.retain(|(lod, receiver)| loop {
+					match receiver.try_recv() {
+						Ok(tile) => ready.push_back((*lod, tile)),
+						Err(TryRecvError::Empty) => break true,
+						Err(TryRecvError::Disconnected) => break false,
+					}
+				});
+			}
 
-		{
-			if let UploadStatus::Uploads | UploadStatus::Resized = ret {
-				tracy::zone!("Tile Map Upload");
-
-				queue.write_texture(
-					self.tile_map.as_image_copy(),
-					unsafe {
-						std::slice::from_raw_parts(
-							self.tiles.as_ptr() as _,
-							self.tiles.len() * std::mem::size_of::<TileOffset>(),
-						)
+			// Most frames don't need to evict anything, so the candidates are only found when they do.
+			let layer = self.grid.0 as usize * self.grid.1 as usize;
+			let mut out_of_view = None;
+			let mut in_view = None;
+			let mut can_grow = true;
+
+			let mut uploaded = 0;
+			while uploaded < upload_budget {
+				let (lod, decoded) = match self.ready.pop_front() {
+					Some(x) => x,
+					None => break,
+				};
+				let index = Self::index(&self.datasets, self.grid, lod, decoded.lat, decoded.lon);
+				self.requested.remove(&index);
+
+				// The tile went out of view while it was being decoded.
+				if used[index] == 0 {
+					continue;
+				}
+
+				ret = UploadStatus::Uploads;
+				let tile = match decoded.tile {
+					Some(Ok(x)) => x,
+					Some(Err(e)) => {
+						log::error!("Error loading tile: {:?}", e);
+						self.tiles[index] = self.atlas.not_found();
+						changed = true;
+						continue;
 					},
-					ImageDataLayout {
-						offset: 0,
-						bytes_per_row: Some(NonZeroU32::new(std::mem::size_of::<TileOffset>() as u32 * 360).unwrap()),
-						rows_per_image: Some(NonZeroU32::new(180).unwrap()),
+					None => {
+						self.tiles[index] = self.atlas.not_found();
+						changed = true;
+						continue;
 					},
-					Extent3d {
-						width: 360,
-						height: 180,
-						depth_or_array_layers: 1,
+				};
+
+				let data = tile.merged_heights();
+				let mut offset = self
+					.atlas
+					.upload_tile(queue, &data, &tile.hillshade, tile.width, tile.height);
+				if offset.is_none() {
+					let candidates = out_of_view.get_or_insert_with(|| self.eviction_candidates(false));
+					changed |= !Self::collect_tiles(
+						&mut self.atlas,
+						&mut self.tiles,
+						candidates,
+						layer,
+						usize::MAX,
+						tile.width,
+						tile.height,
+					)
+					.is_empty();
+					offset = self
+						.atlas
+						.upload_tile(queue, &data, &tile.hillshade, tile.width, tile.height);
+				}
+				if offset.is_none() && can_grow {
+					let (unloaded, not_found) = (self.atlas.unloaded(), self.atlas.not_found());
+					can_grow = self.atlas.grow(device, queue);
+					if can_grow {
+						// Tiles keep their place in the larger atlas, but the sentinels move to its new edges.
+						for offset in self.tiles.iter_mut() {
+							if *offset == unloaded {
+								*offset = self.atlas.unloaded();
+							} else if *offset == not_found {
+								*offset = self.atlas.not_found();
+							}
+						}
+						resized = true;
+						changed = true;
+						self.parked.clear();
+						offset = self
+							.atlas
+							.upload_tile(queue, &data, &tile.hillshade, tile.width, tile.height);
+					}
+				}
+				if offset.is_none() {
+					// Finer tiles fall back to coarser ones, so tiles in view only make space for coarser tiles.
+					let candidates = in_view.get_or_insert_with(|| self.eviction_candidates(true));
+					let evicted = Self::collect_tiles(
+						&mut self.atlas,
+						&mut self.tiles,
+						candidates,
+						layer,
+						lod,
+						tile.width,
+						tile.height,
+					);
+					changed |= !evicted.is_empty();
+					self.parked.extend(evicted);
+					offset = self
+						.atlas
+						.upload_tile(queue, &data, &tile.hillshade, tile.width, tile.height);
+				}
+
+				match offset {
+					Some(offset) => {
+						self.tiles[index] = offset;
+						changed = true;
+						uploaded += 1;
+					},
+					None => {
+						// The decoded cache keeps the tile, so it is cheap to request again once there is space.
+						self.parked.insert(index);
+						full = true;
 					},
-				);
+				}
 			}
 		}
 
-		ret
-	}
+		self.tile_status.unmap();
 
-	pub fn clear(&mut self, radians_per_pixel: f32) {
-		for offset in self.tiles.iter_mut() {
-			*offset = self.atlas.unloaded();
+		if resized {
+			ret = UploadStatus::Resized;
+		} else if full {
+			ret = UploadStatus::AtlasFull;
 		}
-		self.atlas.clear(radians_per_pixel);
-	}
-
-	pub fn tile_map(&self) -> &TextureView { &self.tile_map_view }
-
-	pub fn tile_status(&self) -> &Buffer { &self.tile_status }
-
-	pub fn atlas(&self) -> &TextureView { &self.atlas.view }
-
-	pub fn tile_size(&self) -> u32 { self.atlas.datasets[self.atlas.curr_dataset].metadata().resolution as _ }
-}
 
-struct Atlas {
-	datasets: Vec<Dataset>,
-	lod_densities: Vec<f32>,
-	atlas: Texture,
-	view: TextureView,
-	width: u32,
-	height: u32,
-	curr_dataset: usize,
-	curr_offset: TileOffset,
-	collected_tiles: Vec<TileOffset>,
-}
+		if changed {
+			tracy::zone!("Tile Map Upload");
 
-impl Atlas {
-	fn new(device: &Device, datasets: Vec<PathBuf>) -> Result<Self, LoadError> {
-		let datasets: Result<Vec<_>, LoadError> = datasets.into_iter().map(|dir| Dataset::load(&dir)).collect();
-		let datasets = datasets?;
+			queue.write_texture(
+				self.tile_map.as_image_copy(),
+				unsafe {
+					std::slice::from_raw_parts(
+						self.tiles.as_ptr() as _,
+						self.tiles.len() * std::mem::size_of::<TileOffset>(),
+					)
+				},
+				ImageDataLayout {
+					offset: 0,
+					bytes_per_row: Some(
+						NonZeroU32::new(std::mem::size_of::<TileOffset>() as u32 * self.grid.0).unwrap(),
+					),
+					rows_per_image: Some(NonZeroU32::new(self.grid.1).unwrap()),
+				},
+				Extent3d {
+					width: self.grid.0,
+					height: self.grid.1,
+					depth_or_array_layers: self.datasets.len() as u32,
+				},
+			);
+		}
 
-		let lod_densities = datasets
-			.iter()
-			.map(|x| radians_per_pixel(x.metadata().resolution as _, 1.0f32.to_radians()))
-			.collect();
+		ret
+	}
 
-		let (width, height) = (4096, 4096);
-		let limits = device.limits();
-		let width = width.min(limits.max_texture_dimension_2d);
-		let height = height.min(limits.max_texture_dimension_2d);
-		let (atlas, view) = Self::make_atlas(device, width, height);
+	/// If tiles that were used in the last frame are still being decoded or waiting to be uploaded.
+	pub fn is_loading(&self) -> bool { !self.requested.is_empty() }
 
-		Ok(Self {
-			curr_dataset: datasets.len(),
-			datasets,
-			lod_densities,
-			atlas,
-			view,
-			width,
-			height,
-			curr_offset: TileOffset::default(),
-			collected_tiles: Vec::new(),
-		})
-	}
+	pub fn tile_map(&self) -> &TextureView { &self.tile_map_view }
 
-	fn get_dataset_for_angle(&self, radians_per_pixel: f32) -> usize {
-		let mut index = 0;
-		for (i, &density) in self.lod_densities.iter().enumerate().rev() {
-			if radians_per_pixel >= density {
-				index = i;
-				break;
-			}
-		}
+	pub fn tile_status(&self) -> &Buffer { &self.tile_status }
 
-		index
-	}
+	pub fn lods(&self) -> &Buffer { &self.lods }
 
-	fn needs_clear(&self, radians_per_pixel: f32) -> bool {
-		self.get_dataset_for_angle(radians_per_pixel) != self.curr_dataset
-	}
+	pub fn atlas(&self) -> &TextureView { self.atlas.view() }
 
-	fn clear(&mut self, radians_per_pixel: f32) {
-		self.curr_offset = TileOffset::default();
-		self.collected_tiles.clear();
-		self.curr_dataset = self.get_dataset_for_angle(radians_per_pixel)
-	}
+	pub fn hillshade_atlas(&self) -> &TextureView { self.atlas.hillshade_view() }
 
-	fn return_tile(&mut self, tile: TileOffset) { self.collected_tiles.push(tile); }
+	pub fn atlas_stats(&self) -> AtlasStats { self.atlas.stats() }
 
-	fn upload_tile(&mut self, queue: &Queue, tile: &[u16], hillshade: &[u8]) -> Option<TileOffset> {
-		tracy::zone!("Tile Upload");
+	/// The datasets of each LOD, from the finest to the coarsest.
+	pub fn datasets(&self) -> &[Arc<CachedDataset>] { &self.datasets }
 
-		let res = self.datasets[self.curr_dataset].metadata().resolution as u32;
+	/// The resident tiles that are in view or out of view, in reverse order of eviction.
+	///
+	/// Tiles out of view are evicted from the least recently used, and the finest of those last used in the same frame.
+	/// Tiles in view are evicted from the finest. Tiles of the coarsest LOD are never evicted while they are in view,
+	/// and only after every other tile when they are not, since every other LOD falls back to them.
+	fn eviction_candidates(&self, in_view: bool) -> Vec<usize> {
+		let layer = self.grid.0 as usize * self.grid.1 as usize;
+		let coarsest = self.datasets.len() - 1;
+		let mut candidates: Vec<_> = self
+			.tiles
+			.iter()
+			.enumerate()
+			.filter(|&(index, &offset)| {
+				offset != self.atlas.unloaded()
+					&& offset != self.atlas.not_found()
+					&& (self.last_used[index] == self.frame) == in_view
+					&& !(in_view && index / layer == coarsest)
+			})
+			.map(|(index, _)| index)
+			.collect();
 
-		let ret = if let Some(tile) = self.collected_tiles.pop() {
-			tile
+		if in_view {
+			candidates.sort_unstable_by_key(|&index| Reverse(index / layer));
 		} else {
-			let ret = self.curr_offset;
-			if ret.y + res >= self.height {
-				return None;
-			} else {
-				ret
-			}
-		};
-
-		queue.write_texture(
-			ImageCopyTexture {
-				texture: &self.atlas,
-				mip_level: 0,
-				origin: Origin3d {
-					x: ret.x as _,
-					y: ret.y as _,
-					z: 0,
-				},
-				aspect: TextureAspect::All,
-			},
-			unsafe { std::slice::from_raw_parts(tile.as_ptr() as _, tile.len() * 2) },
-			ImageDataLayout {
-				offset: 0,
-				bytes_per_row: Some(NonZeroU32::new(2 * res).unwrap()),
-				rows_per_image: Some(NonZeroU32::new(res).unwrap()),
-			},
-			Extent3d {
-				width: res,
-				height: res,
-				depth_or_array_layers: 1,
-			},
-		);
-
-		self.curr_offset.x += res;
-		if self.curr_offset.x + res >= self.width {
-			self.curr_offset.x = 0;
-			self.curr_offset.y += res;
+			candidates.sort_unstable_by_key(|&index| {
+				Reverse((index / layer == coarsest, self.last_used[index], index / layer))
+			});
 		}
 
-		Some(ret)
+		candidates
 	}
 
-	fn collect_tiles(&mut self, used: &[u32], tiles: &mut [TileOffset], start: usize) -> bool {
+	/// Evict `candidates` from the end while they are of LODs finer than `lod`, until there is space for a `width` *
+	/// `height` tile. Returns the evicted tiles.
+	fn collect_tiles(
+		atlas: &mut Atlas, tiles: &mut [TileOffset], candidates: &mut Vec<usize>, layer: usize, lod: usize, width: u32,
+		height: u32,
+	) -> Vec<usize> {
 		tracy::zone!("Tile GC");
 
-		let mut needed = 1;
-		let mut collected = 0;
-		for (&used, offset) in used[start + 1..].iter().zip(tiles[start + 1..].iter_mut()) {
-			if used == 1 && *offset == self.unloaded() {
-				needed += 1;
-			} else {
-				if *offset != self.unloaded() && *offset != self.not_found() {
-					self.collected_tiles.push(*offset);
-					*offset = self.unloaded();
-					collected += 1;
-				}
+		let mut evicted = Vec::new();
+		while !atlas.has_space(width, height) {
+			let index = match candidates.last() {
+				Some(&index) if index / layer < lod => index,
+				_ => break,
+			};
+			candidates.pop();
+
+			let offset = &mut tiles[index];
+			if *offset != atlas.unloaded() && *offset != atlas.not_found() {
+				atlas.free(*offset);
+				*offset = atlas.unloaded();
+				evicted.push(index);
 			}
 		}
 
-		collected >= needed
+		evicted
 	}
 
-	fn recreate_atlas(&mut self, device: &Device) -> bool {
-		let limits = device.limits();
-		if self.width == limits.max_texture_dimension_2d && self.height == limits.max_texture_dimension_2d {
-			log::error!("Atlas is too large to fit in device limits");
-			return false;
-		}
-
-		let width = (self.width * 2).min(limits.max_texture_dimension_2d);
-		let height = (self.height * 2).min(limits.max_texture_dimension_2d);
-		let (atlas, view) = Self::make_atlas(device, width, height);
-
-		self.atlas = atlas;
-		self.view = view;
-		self.width = width;
-		self.height = height;
+	/// The index of a tile in the tile map and tile status buffer.
+	fn index(datasets: &[Arc<CachedDataset>], grid: (u32, u32), lod: usize, lat: i16, lon: i16) -> usize {
+		let tiles_per_degree = datasets[lod].metadata().tiles_per_degree as i16;
+		let (x, y) = (
+			(lon + 180 * tiles_per_degree) as usize,
+			(lat + 90 * tiles_per_degree) as usize,
+		);
+		(lod * grid.1 as usize + y) * grid.0 as usize + x
+	}
 
-		true
+	/// The (lod, lat, lon) of the tile at `index`.
+	fn key(datasets: &[Arc<CachedDataset>], grid: (u32, u32), index: usize) -> (usize, i16, i16) {
+		let layer = grid.0 as usize * grid.1 as usize;
+		let (lod, index) = (index / layer, index % layer);
+		let tiles_per_degree = datasets[lod].metadata().tiles_per_degree as i16;
+		let lon = (index % grid.0 as usize) as i16 - 180 * tiles_per_degree;
+		let lat = (index / grid.0 as usize) as i16 - 90 * tiles_per_degree;
+		(lod, lat, lon)
 	}
 
-	fn make_atlas(device: &Device, width: u32, height: u32) -> (Texture, TextureView) {
-		let descriptor = TextureDescriptor {
-			label: Some("Heightmap Atlas"),
+	fn make_tile_map(device: &Device, grid: (u32, u32), lods: u32) -> (Texture, TextureView, Buffer) {
+		let tile_map = device.create_texture(&TextureDescriptor {
+			label: Some("Tile Map"),
 			size: Extent3d {
-				width,
-				height,
-				depth_or_array_layers: 1,
+				width: grid.0,
+				height: grid.1,
+				depth_or_array_layers: lods,
 			},
 			mip_level_count: 1,
 			sample_count: 1,
 			dimension: TextureDimension::D2,
-			format: TextureFormat::R16Uint,
+			format: TextureFormat::Rg32Uint,
 			usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-		};
-
-		let atlas = device.create_texture(&descriptor);
-		let view = atlas.create_view(&TextureViewDescriptor {
-			label: Some("Heightmap Atlas View"),
+		});
+		let tile_map_view = tile_map.create_view(&TextureViewDescriptor {
+			label: Some("Tile Map View"),
+			dimension: Some(TextureViewDimension::D2Array),
 			..Default::default()
 		});
 
-		(atlas, view)
-	}
-
-	fn unloaded(&self) -> TileOffset { TileOffset { x: 0, y: self.height } }
+		let tile_status = device.create_buffer(&BufferDescriptor {
+			label: Some("Tile Status"),
+			size: grid.0 as u64 * grid.1 as u64 * lods as u64 * 4,
+			usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ | BufferUsages::STORAGE,
+			mapped_at_creation: false,
+		});
 
-	fn not_found(&self) -> TileOffset { TileOffset { x: self.width, y: 0 } }
+		(tile_map, tile_map_view, tile_status)
+	}
 }